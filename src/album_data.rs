@@ -1,18 +1,24 @@
-use chrono::naive::NaiveDateTime;
+use chrono::naive::NaiveTime;
 use chrono::Duration;
-use chrono::NaiveTime;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ffi::OsString;
-use std::fs::{self, metadata, File};
+use std::fs::{self, File};
 use std::io::Write;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::time::SystemTime;
 
+use crate::album_entry::{AlbumEntry, Placeholder};
 use crate::album_name::AlbumName;
-use crate::config::TrackData;
+use crate::cache::Cache;
+use crate::command::{self, run};
+use crate::config::{Stage, TrackData};
+use crate::export_format::Export;
+use crate::manifest::{commit_file, hash_file, partial_file, Manifest};
+use crate::timeline::Timeline;
 use crate::track_name::TrackName;
+use crate::tracklist::{format_timestamp, Tracklist, TracklistConfig, TracklistEntry};
 
 #[derive(Clone, Debug)]
 pub struct AlbumData<'a> {
@@ -29,80 +35,285 @@ impl<'a> AlbumData<'a> {
             album_name,
         }
     }
-    pub fn tracks(&self) -> &Vec<String> {
+    pub fn entries(&self) -> &Vec<AlbumEntry> {
         &self.album_config.tracks
     }
+    pub fn tracks(&self) -> Vec<String> {
+        self.entries().iter().map(|e| e.track()).collect()
+    }
+    // (title inside this album, track data)
+    // Track data is None for placeholders in a draft album
+    pub fn titled_tracks(&self, matches: &clap::ArgMatches) -> Vec<(String, Option<TrackData>)> {
+        self.entries()
+            .iter()
+            .map(|entry| {
+                let track_data = self.track_data(entry, matches);
+                (entry.title(track_data.as_ref()), track_data)
+            })
+            .collect()
+    }
+    // Tracklists, playlists, and such written next to the master
+    pub fn tracklist_files(&self) -> Vec<String> {
+        self.album_config
+            .tracklist
+            .clone()
+            .unwrap_or_else(TracklistConfig::defaults)
+            .iter()
+            .map(TracklistConfig::file_name)
+            .collect()
+    }
+    fn track_data(&self, entry: &AlbumEntry, matches: &clap::ArgMatches) -> Option<TrackData> {
+        let track_str: OsString = entry.track().into();
+        let track_name = TrackName::new(&track_str, matches);
+        if track_name.source_dir().join("config.toml").exists() {
+            Some(TrackData::load_from_track(&track_name))
+        } else {
+            assert!(
+                self.is_draft(),
+                "Track '{}' of album '{}' does not exist in tracks/. \
+                 Set `draft = true` in [album] to use a placeholder",
+                entry.track(),
+                self.album_name
+            );
+            None
+        }
+    }
+    pub fn is_draft(&self) -> bool {
+        self.album_config.draft == Some(true)
+    }
+    pub fn placeholder(&self) -> Placeholder {
+        Placeholder {
+            kind: self
+                .album_config
+                .placeholder
+                .clone()
+                .unwrap_or_else(|| "silence".to_owned()),
+            length: self
+                .album_config
+                .placeholder_length
+                .unwrap_or_else(|| NaiveTime::from_hms_opt(0, 3, 0).unwrap()),
+        }
+    }
+    fn print_progress(&self, track_datas: &[(String, Option<TrackData>)]) {
+        let done = track_datas.iter().filter(|(_, td)| td.is_some()).count();
+        println!(
+            "> Draft progress: {}/{} tracks done",
+            done,
+            track_datas.len()
+        );
+        for (entry, (title, td)) in self.entries().iter().zip(track_datas) {
+            match td {
+                Some(_) => println!(">   [x] {} ({})", title, entry.track()),
+                None => println!(
+                    ">   [ ] {} -- missing tracks/{}, using {}",
+                    title,
+                    entry.track(),
+                    self.placeholder()
+                ),
+            }
+        }
+    }
+    // tags shared by every exported file of this album
+    pub fn album_tags(&self) -> Vec<(&'static str, String)> {
+        let album = &self.album_config.album;
+        let mut tags = vec![("album", album.title.clone())];
+        if let Some(artist) = &album.artist {
+            tags.push(("artist", artist.clone()));
+            tags.push(("album_artist", artist.clone()));
+        }
+        if let Some(year) = album.year {
+            tags.push(("date", year.to_string()));
+        }
+        tags
+    }
+
+    // silence between tracks
+    fn gap_seconds() -> f64 {
+        2.0
+    }
+
+    // Prints a table of track lengths without building anything
+    pub fn plan(&self, matches: &clap::ArgMatches) {
+        let gap = Duration::milliseconds((Self::gap_seconds() * 1000.0) as i64);
+        let placeholder = self.placeholder();
+        let rows: Vec<(String, Duration, &str, &str)> = self
+            .entries()
+            .iter()
+            .map(|entry| {
+                let track_data = self.track_data(entry, matches);
+                let title = entry.title(track_data.as_ref());
+                let track_data = match track_data {
+                    Some(track_data) => track_data,
+                    None => {
+                        let length = placeholder
+                            .length
+                            .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+                        return (title, length, "placeholder", "missing");
+                    }
+                };
+                let track_str: OsString = entry.track().into();
+                let processed = TrackName::new(&track_str, matches)
+                    .dest_dir()
+                    .join(TrackData::processed_filename());
+                let updates = &track_data.updates;
+                let stale = updates.needs_build_update
+                    || updates.needs_raw_update
+                    || updates.needs_preprocessed_update
+                    || updates.needs_ffmpeg_update;
+                let (length, source) = if processed.exists() {
+                    let (samples, rate) = Timeline::probe(&processed);
+                    (
+                        Duration::milliseconds((samples * 1000 / rate) as i64),
+                        "cached",
+                    )
+                } else {
+                    (track_data.estimate_runtime(), "estimated")
+                };
+                let status = match (source, stale) {
+                    ("estimated", _) => "not built",
+                    (_, true) => "stale",
+                    _ => "up to date",
+                };
+                (title, entry.trimmed(length), source, status)
+            })
+            .collect();
+
+        let total = rows
+            .iter()
+            .fold(Duration::zero(), |acc, (_, length, _, _)| {
+                acc + *length + gap
+            });
+        let with_hours = total >= Duration::hours(1);
+        let timestamp = |d: Duration| format_timestamp(d, with_hours);
+        let title_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(5);
+
+        println!("Plan for album '{}'", self.album_config.title);
+        println!(
+            "{:>3}  {:>8}  {:>8}  {:<w$}  {:<11}  Status",
+            "#",
+            "Start",
+            "Length",
+            "Title",
+            "Source",
+            w = title_width
+        );
+        let mut start = Duration::zero();
+        for (i, (title, length, source, status)) in rows.iter().enumerate() {
+            println!(
+                "{:>3}  {:>8}  {:>8}  {:<w$}  {:<11}  {}",
+                i + 1,
+                timestamp(start),
+                timestamp(*length),
+                title,
+                source,
+                status,
+                w = title_width
+            );
+            start = start + *length + gap;
+        }
+        println!(
+            "Total: {} including {} of gaps between tracks",
+            timestamp(total),
+            timestamp(gap * rows.len() as i32)
+        );
+        if let Some(target) = self.album_config.target_length {
+            let target = target.signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+            if total <= target {
+                println!(
+                    "Target: {}, {} left to fill",
+                    timestamp(target),
+                    timestamp(target - total)
+                );
+            } else {
+                println!(
+                    "Target: {}, over by {}",
+                    timestamp(target),
+                    timestamp(total - target)
+                );
+            }
+        }
+    }
 
     pub fn compile(&self, matches: &clap::ArgMatches) {
         self.create_dirs();
 
         //////////////// setup and cache
 
-        let mut out_of_date = false;
-        let track_datas: Vec<TrackData> = self
-            .tracks()
+        let placeholder = self.placeholder();
+        let track_datas: Vec<(String, Option<TrackData>)> = self
+            .entries()
             .iter()
-            .map(|track| {
-                let track_str: OsString = track.into();
-                let track_name = TrackName::new(&track_str, matches);
-                let track_data = TrackData::load_from_track(&track_name);
-                crate::toplevel_track::build_track(track_name.clone());
-
-                let old_path = track_name.dest_dir().join(TrackData::processed_filename());
+            .map(|entry| {
+                let track_data = self.track_data(entry, matches);
+                let title = entry.title(track_data.as_ref());
                 let new_path = self
                     .album_name
                     .dest_dir()
                     .join(Self::track_dir_name())
-                    .join(format!("{}.flac", track_data.output().name.clone()));
-
-                // yeah it's copy and paste but whatever
-                let time_old = metadata(&old_path).ok().and_then(|m| {
-                    m.modified().ok().map(|d| {
-                        NaiveDateTime::from_timestamp(
-                            d.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
-                            0,
-                        )
-                    })
-                });
-                let time_new = metadata(&new_path).ok().and_then(|m| {
-                    m.modified().ok().map(|d| {
-                        NaiveDateTime::from_timestamp(
-                            d.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
-                            0,
-                        )
-                    })
-                });
+                    .join(format!("{}.flac", title));
+
+                let track_data = match track_data {
+                    Some(track_data) => track_data,
+                    None => {
+                        if !entry.is_cached(
+                            &self.album_name.dest_dir(),
+                            &new_path,
+                            Some(&placeholder),
+                            None,
+                        ) {
+                            command::set_stage(format!("placeholder for '{}'", title));
+                            entry.render_placeholder(
+                                &placeholder,
+                                &new_path,
+                                &self.album_name.dest_dir(),
+                            );
+                        }
+                        return (title, None);
+                    }
+                };
+                let track_str: OsString = entry.track().into();
+                let track_name = TrackName::new(&track_str, matches);
+                crate::toplevel_track::build_track(track_name.clone());
+
+                let old_path = track_name.dest_dir().join(TrackData::processed_filename());
 
                 // only copy if required
-                match (time_old, time_new) {
-                    (Some(old), Some(new)) if new > old => (),
-                    _ => {
-                        out_of_date = true;
-                        fs::copy(old_path, new_path).map(|_| ()).unwrap()
-                    }
+                let source = Stage::Clip.output_hash(&track_name);
+                if !entry.is_cached(
+                    &self.album_name.dest_dir(),
+                    &new_path,
+                    None,
+                    source.as_deref(),
+                ) {
+                    command::set_stage(format!("album copy of '{}'", title));
+                    entry.render(
+                        &old_path,
+                        &new_path,
+                        &self.album_name.dest_dir(),
+                        source.as_deref(),
+                    );
                 }
 
-                track_data
+                (title, Some(track_data))
             })
             .collect();
 
+        if self.is_draft() {
+            self.print_progress(&track_datas);
+        }
+
         let dest_file = self
             .album_name
             .dest_dir()
             .join(format!("{}.flac", self.album_config.album.title));
 
-        if !out_of_date && dest_file.exists() {
-            println!(">Album up to date; continuing");
-        } else {
-            ////////////// master-cut creation
-
-            let empty_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-                .join("target")
-                .join("silence")
-                .with_extension("flac");
+        ////////////// gap between tracks
 
+        // the gap never changes, so it's only made once
+        let empty_file = self.silence_file();
+        if !empty_file.exists() {
             assert!(
-                Command::new("sox")
+                run(Command::new("sox")
                     .arg("-n")
                     .arg("-r")
                     .arg("44100")
@@ -111,32 +322,55 @@ impl<'a> AlbumData<'a> {
                     .arg("-c")
                     .arg("2")
                     .arg("-L")
-                    .arg(empty_file.clone().into_os_string())
+                    .arg(partial_file(&empty_file))
                     .arg("trim")
                     .arg("0.0")
-                    .arg("2.0")
+                    .arg(Self::gap_seconds().to_string())
                     .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .expect("Sox command failed")
-                    .status
-                    .success(),
+                    .stderr(Stdio::inherit()))
+                .expect("Sox command failed")
+                .status
+                .success(),
                 "Sox command failed"
             );
+            commit_file(&empty_file);
+        }
+
+        let titles: Vec<String> = track_datas.iter().map(|(title, _)| title.clone()).collect();
+        self.remove_old_entries(&titles);
+        let inputs = self.master_inputs(&titles);
+        assert!(
+            command::dry_run() || inputs.contains_key("silence"),
+            "gap file missing"
+        );
+        let master_key = format!("{}.flac", self.album_config.album.title);
+        let master_up_to_date = self.master_stale_reason(&inputs).is_none();
+        // the master is written under a partial name until it's verified
+        let master_file = if master_up_to_date {
+            println!(">Album up to date; continuing");
+            dest_file.clone()
+        } else {
+            ////////////// master-cut creation
+            command::set_stage(format!("mastering of album '{}'", self.album_name));
+
+            Manifest::remove(&self.album_name.dest_dir(), "master");
+            if !command::dry_run() {
+                fs::remove_file(Album::cache_file(self.album_name)).ok();
+            }
+            let partial = partial_file(&dest_file);
 
             let files: Vec<OsString> = track_datas
                 .iter()
-                .map(|td| {
+                .map(|(title, _)| {
                     self.album_name
                         .dest_dir()
                         .join(Self::track_dir_name())
-                        .join(format!("{}.flac", td.output().name.clone()))
+                        .join(format!("{}.flac", title))
                 })
-                .map(|p| {
+                .flat_map(|p| {
                     std::iter::once(p.into_os_string())
                         .chain(std::iter::once(empty_file.clone().into_os_string()))
                 })
-                .flatten()
                 .collect();
 
             println!("Writing album full-format file");
@@ -147,21 +381,20 @@ impl<'a> AlbumData<'a> {
                 .collect::<Vec<String>>()
                 .join("\n");
 
-            let fpath = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-                .join("target")
-                .join("tracklist_internal");
-            let mut ffile = File::create(fpath.clone()).unwrap();
-
-            ffile.write_all(fstr.as_bytes()).unwrap();
+            let fpath = self.scratch_dir().join("concat.txt");
+            if !command::dry_run() {
+                let mut ffile = File::create(fpath.clone()).unwrap();
+                ffile.write_all(fstr.as_bytes()).unwrap();
+            }
 
             println!(
                 "---> ffmpeg -f concat -safe 0 -i {} -y {:?}",
                 fpath.clone().into_os_string().into_string().unwrap(),
-                dest_file.clone().clone().into_os_string()
+                partial.clone().into_os_string()
             );
 
             assert!(
-                Command::new("ffmpeg")
+                run(Command::new("ffmpeg")
                     .arg("-f")
                     .arg("concat")
                     .arg("-safe")
@@ -169,74 +402,103 @@ impl<'a> AlbumData<'a> {
                     .arg("-i")
                     .arg(fpath.into_os_string())
                     .arg("-y")
-                    .arg(dest_file.into_os_string())
+                    .arg(&partial)
                     .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .expect("Build command failed")
-                    .status
-                    .success(),
+                    .stderr(Stdio::inherit()))
+                .expect("Build command failed")
+                .status
+                .success(),
                 "Build command failed"
             );
-        }
+            partial
+        };
 
-        ////////////////// tracklist generation
-        let mut tracklist_pre = self
-            .tracks()
-            .iter()
-            .map(|track| {
-                let track_str: OsString = track.into();
-                let track_name = TrackName::new(&track_str, matches);
-                track_name.get_runtime()
-            })
-            .fold(vec![], |mut acc, cur| {
-                let prev_time = acc.last().unwrap_or(&Duration::seconds(-1)).clone();
-                let cur_absolute_time = cur + prev_time + Duration::seconds(2);
-                acc.push(cur_absolute_time);
-                acc
-            });
+        if command::dry_run() {
+            // everything from here on reads the built files
+            println!("---> Track boundaries, cue sheets, and tracklists come from the built files");
+            return;
+        }
 
-        let mut tracklist = vec![Duration::seconds(0)];
-        tracklist.append(&mut tracklist_pre);
+        ////////////////// track boundaries
 
-        let format_string = if tracklist.last().unwrap() >= &Duration::hours(1) {
-            "%H:%M:%S"
-        } else {
-            "%M:%S"
-        };
-
-        let tracklist_string = format!(
-            "{}\n\nTracklist:\n{}\n",
-            self.album_config.title,
-            track_datas
+        let timeline = Timeline::new(
+            &track_datas
                 .iter()
-                .map(|t| t.output().name.clone())
-                .enumerate()
-                .map(|(i, s)| format!(
-                    "{} {}",
-                    (NaiveTime::from_hms(0, 0, 0) + tracklist[i]).format(format_string),
-                    s
-                ))
-                .map(|s| if s.chars().nth(0).unwrap() == '0' {
-                    s.chars().skip(1).collect()
-                } else {
-                    s
+                .map(|(title, _)| {
+                    (
+                        title.clone(),
+                        self.album_name
+                            .dest_dir()
+                            .join(Self::track_dir_name())
+                            .join(format!("{}.flac", title)),
+                    )
                 })
-                .collect::<Vec<String>>()
-                .join("\n")
+                .collect::<Vec<(String, PathBuf)>>(),
+            &empty_file,
         );
+        timeline.save(&self.album_name.dest_dir());
+        timeline.verify(&master_file);
+
+        if !master_up_to_date {
+            crate::cue::embed_cuesheet(&master_file, &timeline);
+            commit_file(&dest_file);
+            let mut manifest = Manifest {
+                inputs,
+                ..Manifest::default()
+            };
+            manifest.hash_outputs(&self.album_name.dest_dir(), &[&master_key]);
+            manifest.save(&self.album_name.dest_dir(), "master");
+            self.album_config
+                .album
+                .cached()
+                .write_cache(self.album_name);
+        }
 
-        let tracklist_path = self
-            .album_name
-            .dest_dir()
-            .join("tracklist")
-            .with_extension("txt");
-        let mut tracklist_file = File::create(tracklist_path.clone()).unwrap();
-
-        tracklist_file
-            .write_all(tracklist_string.as_bytes())
+        let mut cue_file = File::create(
+            self.album_name
+                .dest_dir()
+                .join(format!("{}.cue", self.album_config.album.title)),
+        )
+        .unwrap();
+        cue_file
+            .write_all(
+                timeline
+                    .cue_sheet(
+                        &self.album_config.title,
+                        &format!("{}.flac", self.album_config.title),
+                        "WAVE",
+                    )
+                    .as_bytes(),
+            )
             .unwrap();
 
+        ////////////////// tracklist generation
+        let tracklist = Tracklist {
+            album: &self.album_config.album,
+            entries: timeline
+                .entries
+                .iter()
+                .zip(self.tracks())
+                .zip(&track_datas)
+                .map(|((entry, id), (_, td))| TracklistEntry {
+                    title: entry.title.clone(),
+                    track_id: id.clone(),
+                    credits: td.as_ref().and_then(|td| td.credits()),
+                    start: timeline.duration(entry.start),
+                    duration: timeline.duration(entry.samples),
+                })
+                .collect(),
+            total: timeline.duration(timeline.total_samples),
+        };
+        tracklist.write_all(
+            &self
+                .album_config
+                .tracklist
+                .clone()
+                .unwrap_or_else(TracklistConfig::defaults),
+            &self.album_name.dest_dir(),
+        );
+
         ////////////////// cleaning up
 
         println!(
@@ -244,7 +506,7 @@ impl<'a> AlbumData<'a> {
             self.album_config.title,
             track_datas
                 .into_iter()
-                .map(|t| t.output().name.clone())
+                .map(|(title, _)| title)
                 .enumerate()
                 .map(|(i, s)| format!("{} - {}", i, s))
                 .collect::<Vec<String>>()
@@ -252,12 +514,169 @@ impl<'a> AlbumData<'a> {
         );
     }
 
+    // intermediate files, kept per album so albums can be built side by side
+    fn scratch_dir(&self) -> PathBuf {
+        self.album_name.dest_dir().join("scratch")
+    }
+
+    fn silence_file(&self) -> PathBuf {
+        self.scratch_dir().join("silence.flac")
+    }
+
+    // An entry's copy in individual_tracks
+    fn entry_file(&self, title: &str) -> PathBuf {
+        self.album_name
+            .dest_dir()
+            .join(Self::track_dir_name())
+            .join(format!("{}.flac", title))
+    }
+
+    // Removes copies in individual_tracks, and their records, that no entry has anymore,
+    // like after a track was renamed or taken out of the album
+    fn remove_old_entries(&self, titles: &[String]) {
+        if command::dry_run() {
+            return;
+        }
+        let dest_dir = self.album_name.dest_dir();
+        for (dir, extension) in &[(Self::track_dir_name(), "flac"), ("entries", "toml")] {
+            for file in fs::read_dir(dest_dir.join(dir)).into_iter().flatten() {
+                let path = file.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                if !titles
+                    .iter()
+                    .any(|t| name == format!("{}.{}", t, extension))
+                {
+                    println!("---> Removing {}/{}", dir, name);
+                    fs::remove_file(&path).unwrap();
+                }
+            }
+        }
+    }
+
+    // the master is rebuilt whenever a track or the gap changes
+    fn master_inputs(&self, titles: &[String]) -> BTreeMap<String, String> {
+        let mut inputs: BTreeMap<String, String> = self
+            .entries()
+            .iter()
+            .zip(titles)
+            .filter_map(|(entry, title)| {
+                entry
+                    .output_hash(&self.album_name.dest_dir(), &self.entry_file(title))
+                    .map(|h| (format!("{}/{}.flac", Self::track_dir_name(), title), h))
+            })
+            .collect();
+        if let Some(hash) = hash_file(&self.silence_file()) {
+            inputs.insert("silence".to_owned(), hash);
+        }
+        inputs
+    }
+
+    fn master_stale_reason(&self, inputs: &BTreeMap<String, String>) -> Option<String> {
+        if let Some(reason) = self
+            .album_config
+            .album
+            .cached()
+            .stale_reason(self.album_name)
+        {
+            return Some(reason);
+        }
+        let manifest = match Manifest::load(&self.album_name.dest_dir(), "master") {
+            Some(manifest) => manifest,
+            None => return Some("not built yet".to_owned()),
+        };
+        let master_key = format!("{}.flac", self.album_config.album.title);
+        if let Some(input) = inputs
+            .keys()
+            .chain(manifest.inputs.keys())
+            .find(|k| inputs.get(*k) != manifest.inputs.get(*k))
+        {
+            return Some(format!("{} changed", input));
+        }
+        if manifest.output(&master_key).is_none() {
+            return Some(format!("{} missing", master_key));
+        }
+        manifest.damaged_output(&self.album_name.dest_dir())
+    }
+
+    // Prints whether each entry and the master would be rebuilt, and why
+    // Only reads: tracks are checked the same way `sotclk status track` does
+    pub fn status(&self, matches: &clap::ArgMatches) {
+        let placeholder = self.placeholder();
+        let mut rows: Vec<(String, &str, String)> = vec![];
+        let mut titles = vec![];
+        for entry in self.entries() {
+            let track_str: OsString = entry.track().into();
+            let track_name = TrackName::new(&track_str, matches);
+            let mut track_data = self.track_data(entry, matches);
+            if let Some(track_data) = &mut track_data {
+                track_data.check_sources(&track_name);
+            }
+            let title = entry.title(track_data.as_ref());
+            let album_file = self
+                .album_name
+                .dest_dir()
+                .join(Self::track_dir_name())
+                .join(format!("{}.flac", title));
+            let reason = match &track_data {
+                None => entry.stale_reason(
+                    &self.album_name.dest_dir(),
+                    &album_file,
+                    Some(&placeholder),
+                    None,
+                ),
+                Some(track_data) if !track_data.updates.reasons.is_empty() => {
+                    Some(format!("track {} is stale", entry.track()))
+                }
+                Some(_) => {
+                    let source = Stage::Clip.output_hash(&track_name);
+                    entry.stale_reason(
+                        &self.album_name.dest_dir(),
+                        &album_file,
+                        None,
+                        source.as_deref(),
+                    )
+                }
+            };
+            let status = match &reason {
+                None => "up to date",
+                Some(_) if !album_file.exists() => "missing",
+                Some(_) => "stale",
+            };
+            rows.push((title.clone(), status, reason.unwrap_or_default()));
+            titles.push(title);
+        }
+
+        let master_key = format!("{}.flac", self.album_config.album.title);
+        let master_reason = match rows.iter().find(|(_, status, _)| *status != "up to date") {
+            Some((title, _, _)) => Some(format!("individual track {} is stale", title)),
+            None => self.master_stale_reason(&self.master_inputs(&titles)),
+        };
+        let master_status = match &master_reason {
+            None => "up to date",
+            Some(_) if !self.album_name.dest_dir().join(&master_key).exists() => "missing",
+            Some(_) => "stale",
+        };
+        rows.push((master_key, master_status, master_reason.unwrap_or_default()));
+
+        let width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(5);
+        println!("Album {}", self.album_name);
+        println!("  {:<w$}  {:<10}  Reason", "Entry", "Status", w = width);
+        for (name, status, reason) in rows {
+            let row = format!("  {:<w$}  {:<10}  {}", name, status, reason, w = width);
+            println!("{}", row.trim_end());
+        }
+    }
+
     fn create_dirs(&self) {
+        if command::dry_run() {
+            return;
+        }
         println!("\nCreating album directories...");
         fs::create_dir_all(self.album_name.dest_dir().join(Self::track_dir_name())).unwrap();
+        fs::create_dir_all(self.scratch_dir()).unwrap();
     }
 
-    fn track_dir_name() -> &'static str {
+    pub fn track_dir_name() -> &'static str {
         "individual_tracks"
     }
 }
@@ -265,6 +684,8 @@ impl<'a> AlbumData<'a> {
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct AlbumConfig {
     pub album: Album, // serde crap
+    pub export: Option<Export>,
+    pub tracklist: Option<Vec<TracklistConfig>>,
 }
 
 impl Deref for AlbumConfig {
@@ -278,5 +699,91 @@ impl Deref for AlbumConfig {
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Album {
     pub title: String,
-    pub tracks: Vec<String>,
+    pub artist: Option<String>,
+    pub year: Option<u32>,
+    pub description: Option<String>,
+    pub notes: Option<String>,
+    pub links: Option<Vec<Link>>,
+    pub draft: Option<bool>,
+    pub placeholder: Option<String>,
+    pub placeholder_length: Option<NaiveTime>,
+    pub target_length: Option<NaiveTime>,
+    pub tracks: Vec<AlbumEntry>,
+}
+
+impl Album {
+    // What goes in the cache
+    // Entries are all written as tables, since toml can't write a list mixing them with names
+    pub fn cached(&self) -> Self {
+        Self {
+            tracks: self
+                .tracks
+                .iter()
+                .map(|e| AlbumEntry::Table(e.overrides()))
+                .collect(),
+            ..self.clone()
+        }
+    }
+}
+
+// Anything in [album] can end up in the master, so all of it is cached
+impl Cache for Album {
+    fn self_type() -> &'static str {
+        "album"
+    }
+    fn tool() -> Option<&'static str> {
+        Some("ffmpeg")
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Link {
+    pub name: String,
+    pub url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheTarget;
+
+    struct Scratch(PathBuf);
+
+    impl std::fmt::Display for Scratch {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.0.display())
+        }
+    }
+
+    impl CacheTarget for Scratch {
+        fn cache_dir(&self) -> PathBuf {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn mixed_tracks_read_back_from_the_cache() {
+        let album: Album = toml::from_str(
+            r#"
+            title = "Mixed"
+            tracks = ["vmlinuz-linux", { track = "kernelbuild", title = "Building the Kernel" }, "journal"]
+            "#,
+        )
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("sotclk-album-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = Scratch(dir.clone());
+
+        album.cached().write_cache(&target);
+        let written = fs::read_to_string(Album::cache_file(&target)).unwrap();
+        let stale = album.cached().stale_reason(&target);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            toml::from_str::<toml::Value>(&written).is_ok(),
+            "{}",
+            written
+        );
+        assert_eq!(stale, None);
+    }
 }
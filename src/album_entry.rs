@@ -0,0 +1,304 @@
+use chrono::naive::NaiveTime;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::cache::changed_fields;
+use crate::command::{self, run, tool_version};
+use crate::config::TrackData;
+use crate::manifest::{commit_file, hash_file, partial_file};
+
+// One item of `tracks` in an album config
+// Either a track directory name, or a table with album-only overrides:
+//     { track = "kernelbuild", title = "Building the Kernel", trim_end = "0:00:05", gain_db = -3 }
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum AlbumEntry {
+    Name(String),
+    Table(EntryOverrides),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct EntryOverrides {
+    pub track: String,
+    pub title: Option<String>,
+    // how much to cut off the start and end of the track
+    pub trim_start: Option<NaiveTime>,
+    pub trim_end: Option<NaiveTime>,
+    pub gain_db: Option<f64>,
+}
+
+// How missing tracks sound in a draft album
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Placeholder {
+    pub kind: String,
+    pub length: NaiveTime,
+}
+
+// What's currently sitting in individual_tracks for an entry
+// `source` is the hash of the processed.flac it was made from, `output` the hash of the result
+// Tables go last, since toml can't put plain values after them
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+struct EntryRecord {
+    source: Option<String>,
+    output: String,
+    tool: Option<String>,
+    overrides: EntryOverrides,
+    placeholder: Option<Placeholder>,
+}
+
+impl std::fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {}", self.length.format("%H:%M:%S"), self.kind)
+    }
+}
+
+impl AlbumEntry {
+    pub fn overrides(&self) -> EntryOverrides {
+        match self {
+            Self::Name(track) => EntryOverrides {
+                track: track.clone(),
+                title: None,
+                trim_start: None,
+                trim_end: None,
+                gain_db: None,
+            },
+            Self::Table(overrides) => overrides.clone(),
+        }
+    }
+    pub fn track(&self) -> String {
+        self.overrides().track
+    }
+    // Missing tracks are labelled as placeholders
+    pub fn title(&self, track_data: Option<&TrackData>) -> String {
+        match (self.overrides().title, track_data) {
+            (Some(title), Some(_)) => title,
+            (None, Some(td)) => td.output().name.clone(),
+            (title, None) => format!("{} (placeholder)", title.unwrap_or_else(|| self.track())),
+        }
+    }
+    // Length of this track inside the album
+    pub fn trimmed(&self, length: Duration) -> Duration {
+        let overrides = self.overrides();
+        let zero = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+        let trim = |t: Option<NaiveTime>| {
+            t.map(|t| t.signed_duration_since(zero))
+                .unwrap_or_else(Duration::zero)
+        };
+        (length - trim(overrides.trim_start) - trim(overrides.trim_end)).max(Duration::zero())
+    }
+    // Record of the overrides used for the current copy in individual_tracks
+    // Named after that copy, since the same track can be in an album more than once
+    pub fn record_file(album_dir: &Path, album_file: &Path) -> PathBuf {
+        album_dir.join("entries").join(format!(
+            "{}.toml",
+            album_file.file_stem().unwrap().to_string_lossy()
+        ))
+    }
+    fn load_record(&self, album_dir: &Path, album_file: &Path) -> Option<EntryRecord> {
+        fs::read_to_string(Self::record_file(album_dir, album_file))
+            .ok()
+            .and_then(|s| toml::from_str(&s).ok())
+    }
+    pub fn is_cached(
+        &self,
+        album_dir: &Path,
+        album_file: &Path,
+        placeholder: Option<&Placeholder>,
+        source: Option<&str>,
+    ) -> bool {
+        self.stale_reason(album_dir, album_file, placeholder, source)
+            .is_none()
+    }
+    // Why the copy in individual_tracks needs redoing, if it does
+    pub fn stale_reason(
+        &self,
+        album_dir: &Path,
+        album_file: &Path,
+        placeholder: Option<&Placeholder>,
+        source: Option<&str>,
+    ) -> Option<String> {
+        let record = match self.load_record(album_dir, album_file) {
+            Some(record) => record,
+            None => return Some("not built yet".to_owned()),
+        };
+        let file_name = album_file.file_name().unwrap().to_string_lossy();
+        if record.overrides != self.overrides() {
+            let fields: Vec<String> = changed_fields(
+                "",
+                &toml::Value::try_from(&record.overrides).unwrap(),
+                &toml::Value::try_from(self.overrides()).unwrap(),
+            )
+            .into_iter()
+            .map(|c| c.path)
+            .collect();
+            Some(format!("{} changed", fields.join(", ")))
+        } else if record.placeholder.as_ref() != placeholder {
+            Some(match placeholder {
+                Some(_) => "track is missing, using a placeholder".to_owned(),
+                None => "placeholder replaced by the track".to_owned(),
+            })
+        } else if record.source.as_deref() != source {
+            Some(format!("{} changed", TrackData::processed_filename()))
+        } else if record.tool != self.tool(placeholder) {
+            Some("sox changed".to_owned())
+        } else {
+            match hash_file(album_file) {
+                None => Some(format!("{} missing", file_name)),
+                Some(hash) if hash != record.output => Some(format!("{} was modified", file_name)),
+                _ => None,
+            }
+        }
+    }
+    // sox version, if sox is what makes this entry's file
+    fn tool(&self, placeholder: Option<&Placeholder>) -> Option<String> {
+        let overrides = self.overrides();
+        if placeholder.is_some()
+            || overrides.trim_start.is_some()
+            || overrides.trim_end.is_some()
+            || overrides.gain_db.is_some()
+        {
+            Some(tool_version("sox"))
+        } else {
+            None
+        }
+    }
+    // Hash of the entry's file in individual_tracks, as of when it was made
+    pub fn output_hash(&self, album_dir: &Path, album_file: &Path) -> Option<String> {
+        self.load_record(album_dir, album_file).map(|r| r.output)
+    }
+    fn write_record(
+        &self,
+        album_dir: &Path,
+        album_file: &Path,
+        placeholder: Option<&Placeholder>,
+        source: Option<&str>,
+    ) {
+        if command::dry_run() {
+            return;
+        }
+        let record_file = Self::record_file(album_dir, album_file);
+        fs::create_dir_all(record_file.parent().unwrap()).unwrap();
+        fs::write(
+            record_file,
+            toml::to_string(&EntryRecord {
+                overrides: self.overrides(),
+                placeholder: placeholder.cloned(),
+                source: source.map(str::to_owned),
+                output: hash_file(album_file).expect("album track was not written"),
+                tool: self.tool(placeholder),
+            })
+            .unwrap(),
+        )
+        .unwrap();
+    }
+    // Stands in for a track that doesn't exist yet
+    pub fn render_placeholder(
+        &self,
+        placeholder: &Placeholder,
+        album_file: &Path,
+        album_dir: &Path,
+    ) {
+        let seconds = placeholder
+            .length
+            .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+            .num_seconds()
+            .to_string();
+        let mut sox_cmd = Command::new("sox");
+        sox_cmd
+            .arg("-n")
+            .arg("-r")
+            .arg("44100")
+            .arg("-b")
+            .arg("16")
+            .arg("-c")
+            .arg("2")
+            .arg(partial_file(album_file));
+        match placeholder.kind.as_str() {
+            "silence" => sox_cmd.arg("trim").arg("0.0").arg(seconds),
+            "tone" => sox_cmd
+                .arg("synth")
+                .arg(seconds)
+                .arg("sine")
+                .arg("440")
+                .arg("vol")
+                .arg("0.1"),
+            other => panic!(
+                "placeholder '{}' is invalid. \
+		 Valid options are: silence, tone.",
+                other
+            ),
+        };
+        println!(
+            "---> Rendering placeholder for '{}' ({})",
+            self.track(),
+            placeholder
+        );
+        assert!(
+            run(sox_cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit()))
+                .expect("Sox command failed")
+                .status
+                .success(),
+            "Sox command failed"
+        );
+        commit_file(album_file);
+        self.write_record(album_dir, album_file, Some(placeholder), None);
+    }
+    // Copies a track into the album, applying trim and gain if required
+    pub fn render(
+        &self,
+        processed: &Path,
+        album_file: &Path,
+        album_dir: &Path,
+        source: Option<&str>,
+    ) {
+        let overrides = self.overrides();
+        let seconds = |t: Option<NaiveTime>| {
+            t.map(|t| {
+                t.signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+                    .num_milliseconds() as f64
+                    / 1000.0
+            })
+        };
+        let trim_start = seconds(overrides.trim_start);
+        let trim_end = seconds(overrides.trim_end);
+
+        if trim_start.is_none() && trim_end.is_none() && overrides.gain_db.is_none() {
+            if !command::dry_run() {
+                fs::copy(processed, partial_file(album_file)).unwrap();
+            }
+        } else {
+            let mut sox_cmd = Command::new("sox");
+            sox_cmd.arg(processed).arg(partial_file(album_file));
+            if trim_start.is_some() || trim_end.is_some() {
+                sox_cmd
+                    .arg("trim")
+                    .arg(trim_start.unwrap_or(0.0).to_string())
+                    .arg(format!("-{}", trim_end.unwrap_or(0.0)));
+            }
+            if let Some(gain) = overrides.gain_db {
+                sox_cmd.arg("gain").arg(gain.to_string());
+            }
+            println!(
+                "---> sox {}",
+                sox_cmd
+                    .get_args()
+                    .map(|a| a.to_string_lossy().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            );
+            assert!(
+                run(sox_cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit()))
+                    .expect("Sox command failed")
+                    .status
+                    .success(),
+                "Sox command failed"
+            );
+        }
+
+        commit_file(album_file);
+        self.write_record(album_dir, album_file, None, source);
+    }
+}
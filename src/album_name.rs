@@ -15,17 +15,19 @@ impl AlbumName {
         crate::toplevel_album::get_albums(matches)
             .into_iter()
             .find(|tn| tn.get_name() == matches.value_of("album").unwrap())
-            .expect(&format!(
-                "Album '{}' not found in albums/ directory",
-                matches.value_of("album").unwrap()
-            ))
+            .unwrap_or_else(|| {
+                panic!(
+                    "Album '{}' not found in albums/ directory",
+                    matches.value_of("album").unwrap()
+                )
+            })
     }
     pub fn new(name: &OsStr, matches: &clap::ArgMatches) -> Self {
         Self {
             name: name.to_os_string(),
             root_dir: matches
                 .value_of("album_dir")
-                .map(|dirname| PathBuf::from(dirname))
+                .map(PathBuf::from)
                 .unwrap_or(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("albums")),
         }
     }
@@ -0,0 +1,406 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::command;
+
+// What kind of archive a file is, going by its first bytes rather than its name,
+// since urls like codeload's /tar.gz/v5.10 don't have an extension
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+fn format(archive: &Path) -> Format {
+    let mut magic = [0; 262];
+    let read = File::open(archive)
+        .and_then(|mut f| f.read(&mut magic))
+        .unwrap_or_else(|_| panic!("could not read {}", archive.display()));
+    let magic = &magic[..read];
+    if magic.starts_with(b"PK\x03\x04") {
+        Format::Zip
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        Format::TarGz
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Format::TarXz
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Format::TarZst
+    } else if magic.len() == 262 && &magic[257..] == b"ustar" {
+        Format::Tar
+    } else {
+        panic!(
+            "{} isn't a zip, tar, tar.gz, tar.xz, or tar.zst archive",
+            archive.display()
+        )
+    }
+}
+
+// An entry's path with its first `strip` directories taken off, like tar --strip-components
+// None for entries that are stripped away entirely
+// Absolute paths and `..` would land outside of the destination, so they're an error
+fn strip(path: &Path, strip: usize) -> io::Result<Option<PathBuf>> {
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside(path));
+    }
+    let stripped: PathBuf = path
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .skip(strip)
+        .collect();
+    Ok(match stripped.as_os_str().is_empty() {
+        true => None,
+        false => Some(stripped),
+    })
+}
+
+fn outside(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} points outside of the build directory", path.display()),
+    )
+}
+
+// Makes the directories `path` goes in, after checking that what's already there doesn't
+// lead outside of `dest` through a symlink the archive made earlier
+fn make_parent(dest: &Path, path: &Path) -> io::Result<()> {
+    let parent = path.parent().unwrap();
+    let existing = parent.ancestors().find(|p| p.exists()).unwrap();
+    if !existing.canonicalize()?.starts_with(dest.canonicalize()?) {
+        return Err(outside(path));
+    }
+    fs::create_dir_all(parent)
+}
+
+// Symlinks can point anywhere inside of `dest`, but not above it or at an absolute path
+fn check_symlink(dest: &Path, path: &Path, target: &Path) -> io::Result<()> {
+    let mut depth = path.strip_prefix(dest).unwrap().components().count() - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return Err(outside(target)),
+        }
+    }
+    Ok(())
+}
+
+// Replaces whatever is at `path`, for archives that hold the same path twice
+fn clear(path: &Path) {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.is_dir() {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+fn unpack_tar(reader: impl Read, dest: &Path, components: usize) -> io::Result<()> {
+    let mut tar = tar::Archive::new(reader);
+    tar.set_preserve_permissions(true);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() || kind.is_pax_local_extensions() {
+            continue;
+        }
+        let path = match strip(&entry.path()?, components)? {
+            Some(path) => dest.join(path),
+            None => continue,
+        };
+        make_parent(dest, &path)?;
+        if kind.is_hard_link() {
+            // link names are relative to the archive, so they're stripped too
+            let link = entry.link_name()?.unwrap_or_default().into_owned();
+            if let Some(target) = strip(&link, components)? {
+                let target = dest.join(target);
+                make_parent(dest, &target)?;
+                clear(&path);
+                fs::hard_link(target, &path)?;
+            }
+            continue;
+        }
+        if kind.is_symlink() {
+            let target = entry.link_name()?.unwrap_or_default().into_owned();
+            check_symlink(dest, &path, &target)?;
+        }
+        if !kind.is_dir() {
+            clear(&path);
+        }
+        entry.unpack(&path)?;
+    }
+    Ok(())
+}
+
+fn unpack_zip(archive: File, dest: &Path, components: usize) -> io::Result<()> {
+    let mut zip = zip::ZipArchive::new(archive)?;
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let name = file
+            .enclosed_name()
+            .ok_or_else(|| outside(Path::new(file.name())))?
+            .to_owned();
+        let path = match strip(&name, components)? {
+            Some(path) => dest.join(path),
+            None => continue,
+        };
+        make_parent(dest, &path)?;
+        if file.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        clear(&path);
+        io::copy(&mut file, &mut File::create(&path)?)?;
+        if let Some(mode) = file.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+fn unpack(archive: &Path, dest: &Path, components: usize) -> io::Result<()> {
+    let file = File::open(archive)?;
+    match format(archive) {
+        Format::Zip => unpack_zip(file, dest, components),
+        Format::Tar => unpack_tar(file, dest, components),
+        Format::TarGz => unpack_tar(flate2::read::MultiGzDecoder::new(file), dest, components),
+        Format::TarXz => unpack_tar(xz2::read::XzDecoder::new(file), dest, components),
+        Format::TarZst => {
+            zstd::stream::read::Decoder::new(file).and_then(|zst| unpack_tar(zst, dest, components))
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    match fs::symlink_metadata(path).map(|m| m.is_dir()) {
+        Ok(true) => fs::remove_dir_all(path).unwrap(),
+        Ok(false) => fs::remove_file(path).unwrap(),
+        Err(_) => {}
+    }
+}
+
+// Moves `from` to `to`, merging directories that are already there
+// (when two archives unpack into the same directory)
+fn merge(from: &Path, to: &Path) {
+    let to_dir = fs::symlink_metadata(to).map(|m| m.is_dir());
+    if to_dir.is_ok() && from.is_dir() && to_dir.unwrap() {
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            merge(&entry.path(), &to.join(entry.file_name()));
+        }
+        fs::remove_dir(from).unwrap();
+        return;
+    }
+    remove(to);
+    fs::rename(from, to).unwrap();
+}
+
+// scratch/entries lists what an archive last put in `dest`
+fn entries(scratch: &Path) -> Vec<String> {
+    fs::read_to_string(scratch.join("entries"))
+        .map(|s| s.lines().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+// Removes everything an archive put in `dest` last time, so a new version of it
+// doesn't leave behind files the old one had
+pub fn remove_extracted(dest: &Path, scratch: &Path) {
+    if command::dry_run() {
+        return;
+    }
+    for entry in entries(scratch) {
+        remove(&dest.join(entry));
+    }
+}
+
+// Unpacks an archive into `dest`, dropping the first `components` directories of every path
+// Runs in this process, so it doesn't need unzip, tar, xz, or zstd installed
+// `scratch` belongs to this archive alone: it's unpacked in there first, then moved into `dest`
+pub fn extract(archive: &Path, dest: &Path, components: usize, scratch: &Path) {
+    if command::dry_run() {
+        println!(
+            "---> would extract {} into {}",
+            archive.display(),
+            dest.display()
+        );
+        return;
+    }
+    let fresh = scratch.join("partial");
+    remove(&fresh); // left over from an interrupted run
+    fs::create_dir_all(&fresh).unwrap();
+    unpack(archive, &fresh, components)
+        .unwrap_or_else(|e| panic!("Extracting {} failed: {}", archive.display(), e));
+
+    let mut new: Vec<String> = fs::read_dir(&fresh)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    new.sort();
+    // the old entries stay listed until they're moved in, so an interrupt can't lose track of them
+    let listed: Vec<String> = entries(scratch).into_iter().chain(new.clone()).collect();
+    fs::write(
+        scratch.join("entries"),
+        listed
+            .iter()
+            .map(|e| format!("{}\n", e))
+            .collect::<String>(),
+    )
+    .unwrap();
+    for entry in &new {
+        merge(&fresh.join(entry), &dest.join(entry));
+    }
+    fs::write(
+        scratch.join("entries"),
+        new.iter().map(|e| format!("{}\n", e)).collect::<String>(),
+    )
+    .unwrap();
+    fs::remove_dir(&fresh).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sotclk-archive-{}-{}", std::process::id(), name));
+        remove(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn tar_with(entries: &[(&str, tar::EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, kind, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_path(path).unwrap();
+            header.set_entry_type(*kind);
+            header.set_mode(0o644);
+            let data: &[u8] = match kind {
+                tar::EntryType::Regular => b"data",
+                tar::EntryType::Directory => b"",
+                _ => {
+                    header.set_link_name(link).unwrap();
+                    b""
+                }
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn strip_drops_leading_directories() {
+        assert_eq!(
+            strip(Path::new("linux-5.10/Makefile"), 1).unwrap(),
+            Some(PathBuf::from("Makefile"))
+        );
+        assert_eq!(
+            strip(Path::new("./a/b/c"), 1).unwrap(),
+            Some(PathBuf::from("b/c"))
+        );
+        assert_eq!(
+            strip(Path::new("a/b"), 0).unwrap(),
+            Some(PathBuf::from("a/b"))
+        );
+    }
+
+    #[test]
+    fn strip_past_the_path_depth_skips_the_entry() {
+        assert_eq!(strip(Path::new("linux-5.10"), 1).unwrap(), None);
+        assert_eq!(strip(Path::new("a/b"), 5).unwrap(), None);
+    }
+
+    #[test]
+    fn strip_rejects_parent_and_absolute_paths() {
+        assert!(strip(Path::new("../etc/passwd"), 0).is_err());
+        assert!(strip(Path::new("a/../../etc/passwd"), 1).is_err());
+        assert!(strip(Path::new("/etc/passwd"), 0).is_err());
+    }
+
+    #[test]
+    fn symlinks_stay_inside_the_destination() {
+        let dest = Path::new("/build");
+        assert!(check_symlink(dest, &dest.join("a/link"), Path::new("../b")).is_ok());
+        assert!(check_symlink(dest, &dest.join("a/link"), Path::new("./c/../d")).is_ok());
+        assert!(check_symlink(dest, &dest.join("link"), Path::new("..")).is_err());
+        assert!(check_symlink(dest, &dest.join("a/link"), Path::new("../../etc")).is_err());
+        assert!(check_symlink(dest, &dest.join("a/link"), Path::new("b/../../../etc")).is_err());
+        assert!(check_symlink(dest, &dest.join("link"), Path::new("/etc")).is_err());
+    }
+
+    #[test]
+    fn parents_through_outside_symlinks_are_rejected() {
+        let dest = scratch("parent");
+        let outside = scratch("parent-outside");
+        std::os::unix::fs::symlink(&outside, dest.join("escape")).unwrap();
+        std::os::unix::fs::symlink("inner", dest.join("alias")).unwrap();
+        fs::create_dir(dest.join("inner")).unwrap();
+
+        assert!(make_parent(&dest, &dest.join("escape/file")).is_err());
+        assert!(make_parent(&dest, &dest.join("escape/new/file")).is_err());
+        assert!(make_parent(&dest, &dest.join("alias/new/file")).is_ok());
+        assert!(dest.join("inner/new").is_dir());
+        assert!(make_parent(&dest, &dest.join("a/b/file")).is_ok());
+        assert!(dest.join("a/b").is_dir());
+
+        remove(&dest);
+        remove(&outside);
+    }
+
+    #[test]
+    fn tars_cant_write_through_their_own_symlinks() {
+        let dest = scratch("tar-symlink");
+        let outside = scratch("tar-symlink-outside");
+        let tar = tar_with(&[
+            ("link", tar::EntryType::Symlink, outside.to_str().unwrap()),
+            ("link/file", tar::EntryType::Regular, ""),
+        ]);
+        assert!(unpack_tar(&tar[..], &dest, 0).is_err());
+        assert!(!outside.join("file").exists());
+
+        let tar = tar_with(&[
+            ("dir/", tar::EntryType::Directory, ""),
+            ("dir/up", tar::EntryType::Symlink, "../.."),
+            ("dir/up/file", tar::EntryType::Regular, ""),
+        ]);
+        assert!(unpack_tar(&tar[..], &dest, 0).is_err());
+
+        remove(&dest);
+        remove(&outside);
+    }
+
+    #[test]
+    fn tars_cant_hardlink_outside() {
+        let dest = scratch("tar-hardlink");
+        let tar = tar_with(&[("passwd", tar::EntryType::Link, "/etc/passwd")]);
+        assert!(unpack_tar(&tar[..], &dest, 0).is_err());
+        assert!(!dest.join("passwd").exists());
+        remove(&dest);
+    }
+
+    #[test]
+    fn tars_unpack_with_components_stripped() {
+        let dest = scratch("tar-strip");
+        let tar = tar_with(&[
+            ("top/", tar::EntryType::Directory, ""),
+            ("top/file", tar::EntryType::Regular, ""),
+            ("top/sub/", tar::EntryType::Directory, ""),
+            ("top/sub/link", tar::EntryType::Symlink, "../file"),
+        ]);
+        unpack_tar(&tar[..], &dest, 1).unwrap();
+        assert_eq!(fs::read(dest.join("file")).unwrap(), b"data");
+        assert_eq!(fs::read(dest.join("sub/link")).unwrap(), b"data");
+        assert!(!dest.join("top").exists());
+        remove(&dest);
+    }
+}
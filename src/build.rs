@@ -1,24 +1,37 @@
-use chrono::naive::NaiveDateTime;
-use curl::easy::Easy;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, metadata, File};
-use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::time::SystemTime;
 use walkdir::WalkDir;
 
+use crate::archive;
 use crate::cache::Cache;
+use crate::command::{self, run};
 use crate::config::TrackConfig;
+use crate::git_source::GitSource;
+use crate::http_source::HttpSource;
+use crate::lockfile::TrackLock;
+use crate::manifest::{hash_file, Manifest};
+use crate::sources;
 use crate::track_name::TrackName;
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Build {
     pub build_command: String,
-    pub http_sources: Vec<String>,
-    pub git_sources: Vec<String>,
+    pub http_sources: Vec<HttpSource>,
     pub git_update: Option<bool>,
     pub always_rebuild: Option<bool>,
     pub copy_me: bool,
+    // scheduling hints for `build-all -j`
+    pub exclusive: Option<bool>,
+    pub weight: Option<usize>,
+    // tracks that have to be built before this one
+    pub depends_on: Option<Vec<String>>,
+    // run output_command in another track's build directory instead of this one's
+    pub reuse_build_dir: Option<String>,
+    // can hold tables, which toml can't put plain values after
+    pub git_sources: Vec<GitSource>,
 }
 
 impl Cache for Build {
@@ -34,13 +47,74 @@ impl From<TrackConfig> for Build {
 }
 
 impl Build {
-    fn build_lock_file(track_name: &TrackName) -> PathBuf {
+    // What goes in the cache; scheduling hints and build order don't change what gets built
+    // Sources are all written as tables, since toml can't write a list mixing them with strings
+    pub fn cached(&self) -> Self {
+        Self {
+            git_sources: self
+                .git_sources
+                .iter()
+                .map(|s| GitSource::Pinned(s.pin()))
+                .collect(),
+            http_sources: self
+                .http_sources
+                .iter()
+                .map(|s| HttpSource::Pinned(s.pin()))
+                .collect(),
+            exclusive: None,
+            weight: None,
+            depends_on: None,
+            ..self.clone()
+        }
+    }
+    // Every track this one needs built first
+    pub fn dependencies(&self) -> Vec<String> {
+        let mut deps = self.depends_on.clone().unwrap_or_default();
+        if let Some(shared) = &self.reuse_build_dir {
+            if !deps.contains(shared) {
+                deps.push(shared.clone());
+            }
+        }
+        deps
+    }
+    // Holds when the build command last finished, so tracks sharing this build directory
+    // can tell it was rebuilt
+    pub fn build_lock_file(track_name: &TrackName) -> PathBuf {
         track_name.dest_dir().join("build_complete.unlock")
     }
+    // A hash of everything the build directory was last made from: the build config,
+    // checked out commits, downloads, copied files, and when the build command last finished
+    // Tracks reusing the build directory rebuild when it changes
+    pub fn source_state(&self, track_name: &TrackName) -> String {
+        let mut hasher = Sha256::new();
+        if let Some(hash) = hash_file(&Self::cache_file(track_name)) {
+            hasher.update(format!("build.toml {}\n", hash));
+        }
+        for source in &self.git_sources {
+            if let Some(commit) = source.commit(track_name) {
+                hasher.update(format!("git {} {}\n", source.url(), commit));
+            }
+        }
+        for stage in &["http", "local"] {
+            if let Some(manifest) = Manifest::load(&track_name.dest_dir(), stage) {
+                hasher.update(toml::to_string(&manifest).unwrap());
+            }
+        }
+        if let Ok(built) = fs::read_to_string(Self::build_lock_file(track_name)) {
+            hasher.update(format!("built {}\n", built));
+        }
+        format!("{:x}", hasher.finalize())
+    }
     pub fn wipe_build_progress(&self, track_name: &TrackName) {
+        if command::dry_run() {
+            return;
+        }
         std::fs::remove_file(Self::build_lock_file(track_name)).ok();
     }
     pub fn create_dirs(&self, track_name: &TrackName) {
+        if command::dry_run() {
+            return;
+        }
         fs::create_dir_all(track_name.dest_dir().join("build").into_os_string()).unwrap();
         fs::create_dir_all(track_name.dest_dir().join("http").into_os_string()).unwrap();
         fs::create_dir_all(track_name.dest_dir().join("local").into_os_string()).unwrap();
@@ -54,256 +128,281 @@ impl Build {
         println!("--> Building");
         println!("---> {}", self.build_command);
         assert!(
-            Command::new("sh")
+            run(Command::new("sh")
                 .arg("-c")
                 .arg(&self.build_command)
                 .current_dir(track_name.dest_dir().join("build"))
                 .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .output()
-                .expect("Build command failed")
-                .status
-                .success(),
+                .stderr(Stdio::inherit()))
+            .expect("Build command failed")
+            .status
+            .success(),
             "Build command failed"
         );
-        File::create(Self::build_lock_file(track_name)).unwrap();
-        true
-    }
-    fn get_lastmod_upstream(&self, source: &String) -> Option<NaiveDateTime> {
-        let mut easy = Easy::new();
-        easy.url(source).unwrap();
-        let mut last_modified_upstream = None;
-        {
-            let mut transfer = easy.transfer();
-            transfer
-                .header_function(|data| {
-                    let head = String::from_utf8_lossy(data);
-                    if head.starts_with("Last-Modified") {
-                        if let Ok(date) = NaiveDateTime::parse_from_str(
-                            &head
-                                .trim()
-                                .chars()
-                                .skip("Last-Modified: ".len())
-                                .collect::<String>(),
-                            "%a, %d %b %Y %T GMT",
-                        ) {
-                            last_modified_upstream = Some(date);
-                        }
-                    }
-                    head.trim().len() > 0
-                })
-                .unwrap();
-            transfer.perform().ok(); // throw away the error, we expect one from quitting early
+        if !command::dry_run() {
+            fs::write(
+                Self::build_lock_file(track_name),
+                chrono::Local::now().to_rfc3339(),
+            )
+            .unwrap();
         }
-        last_modified_upstream
+        true
     }
-    fn get_lastmod_downstream(
+    // Why a source needs downloading, if it does. Only reads
+    fn http_check(
         &self,
         track_name: &TrackName,
-        source: &String,
-    ) -> Option<NaiveDateTime> {
-        metadata(
-            track_name
-                .dest_dir()
-                .join("http")
-                .join(Path::new(source).file_name().unwrap()),
-        )
-        .ok()
-        .and_then(|m| {
-            m.modified().ok().map(|d| {
-                NaiveDateTime::from_timestamp(
-                    d.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
-                    0,
-                )
-            })
-        })
-    }
-    fn get_lastmod_local(&self, source: &PathBuf) -> Option<NaiveDateTime> {
-        metadata(source).ok().and_then(|m| {
-            m.modified().ok().map(|d| {
-                NaiveDateTime::from_timestamp(
-                    d.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
-                    0,
-                )
-            })
-        })
+        manifest: &Manifest,
+        source: &HttpSource,
+    ) -> Option<String> {
+        let recorded_hash = match manifest.output(&source.key()) {
+            Some(hash) => hash,
+            None => return Some(format!("http source {} not downloaded", source)),
+        };
+        if hash_file(&track_name.dest_dir().join(source.key())).as_ref() != Some(recorded_hash) {
+            return Some(format!("http source {} was modified", source));
+        }
+        // a pinned download never changes, so upstream isn't asked
+        if let Some(sha256) = source.sha256() {
+            return match *recorded_hash == sha256 {
+                true => None,
+                false => Some(format!("http source {} sha256 changed", source)),
+            };
+        }
+        // another track may have downloaded a newer copy already
+        if sources::recorded_hash(&source.url()).is_some_and(|h| h != *recorded_hash) {
+            return Some(format!("http source {} updated upstream", source));
+        }
+        match sources::changed_upstream(&source.url()) {
+            Some(true) => Some(format!("http source {} updated upstream", source)),
+            _ => None, // if idk upstream, assume up to date
+        }
     }
-    pub fn http(&self, track_name: &TrackName, cache: bool) -> bool {
+    // With `locked`, each download has to match the hash in sotclk.lock
+    // A matching copy in target/sources/ is used when upstream has changed since
+    pub fn http(&self, track_name: &TrackName, cache: bool, locked: Option<&TrackLock>) -> bool {
         // returns OutOfDate
+        // The manifest keeps the hash of each download as outputs, and what was extracted from it
+        let mut manifest = match cache {
+            true => Manifest::load(&track_name.dest_dir(), "http").unwrap_or_default(),
+            false => Manifest::default(),
+        };
         let mut out_of_date = false;
         for source in &self.http_sources {
-            let last_modified_downstream = if !cache {
-                None
-            } else {
-                self.get_lastmod_downstream(track_name, source)
-            };
-            let last_modified_upstream = match last_modified_downstream {
-                None => None,
-                _ => self.get_lastmod_upstream(source),
+            let locked_hash = locked.and_then(|l| l.http.get(&source.url()));
+            let needed = match locked_hash {
+                Some(hash) => {
+                    hash_file(&track_name.dest_dir().join(source.key())).as_ref() != Some(hash)
+                }
+                None => self.http_check(track_name, &manifest, source).is_some(),
             };
-
-            match (last_modified_downstream, last_modified_upstream) {
-                (Some(down), Some(up)) if up < down => continue,
-                (Some(_), None) => continue, // if idk upstream, assume up to date
-                _ => {}
+            if needed && Self::fetch_http(track_name, &mut manifest, source, locked_hash, cache) {
+                out_of_date = true;
+            }
+        }
+        // archives can unpack into the same directories, so if one changed they're all
+        // cleared out, then unpacked again in order
+        let archives: Vec<&HttpSource> = self.http_sources.iter().filter(|s| s.extract()).collect();
+        if archives.iter().any(|s| !Self::extracted(&manifest, s)) {
+            let build_dir = track_name.dest_dir().join("build");
+            for source in &archives {
+                archive::remove_extracted(
+                    &build_dir,
+                    &track_name.dest_dir().join(source.extracted_key()),
+                );
+            }
+            for source in &archives {
+                println!("---> extracting {}", source.filename());
+                archive::extract(
+                    &track_name.dest_dir().join(source.key()),
+                    &build_dir,
+                    source.pin().strip_components.unwrap_or(0),
+                    &track_name.dest_dir().join(source.extracted_key()),
+                );
+                if let Some(hash) = manifest.output(&source.key()) {
+                    let extraction = source.extraction(hash);
+                    manifest.outputs.insert(source.extracted_key(), extraction);
+                }
             }
-
             out_of_date = true;
-
-            println!("---> {}", source);
-
-            assert!(
-                Command::new("curl") // Yeah, I'm using curl(1) and libcurl.
-                    .arg(source) // If you hate it so much fix it yourself and PR.
-                    .arg("-O")
-                    .current_dir(track_name.dest_dir().join("http"))
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .expect("Curl http request failed. Aborting.")
-                    .status
-                    .success(),
-                "Curl http request failed. Aborting."
-            );
-            let dl_name = Path::new(source).file_name().unwrap();
+        }
+        manifest.save(&track_name.dest_dir(), "http");
+        out_of_date
+    }
+    // Downloads one source into the track, returning whether its contents changed
+    fn fetch_http(
+        track_name: &TrackName,
+        manifest: &mut Manifest,
+        source: &HttpSource,
+        locked_hash: Option<&String>,
+        cache: bool,
+    ) -> bool {
+        println!("---> {}", source);
+        let dl_key = source.key();
+        let sha256 = locked_hash.cloned().or_else(|| source.sha256());
+        let stored = match sources::download(&source.url(), sha256.as_deref(), cache) {
+            Some(stored) => stored,
+            None => return true, // dry run
+        };
+        sources::link(&stored, &track_name.dest_dir().join(&dl_key));
+        // archives are unpacked there instead
+        if !source.extract() {
             assert!(
-                Command::new("cp")
-                    .arg(track_name.dest_dir().join("http").join(&dl_name))
-                    .arg(track_name.dest_dir().join("build").join(&dl_name))
+                run(Command::new("cp")
+                    .arg(track_name.dest_dir().join(&dl_key))
+                    .arg(track_name.dest_dir().join("build").join(source.filename()))
                     .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .expect("cp failed. Aborting.")
-                    .status
-                    .success(),
+                    .stderr(Stdio::inherit()))
+                .expect("cp failed. Aborting.")
+                .status
+                .success(),
                 "cp failed. Aborting."
             );
         }
-        out_of_date
+        if command::dry_run() {
+            return true;
+        }
+
+        // same bytes as last time means nothing needs rebuilding
+        let new_hash = hash_file(&track_name.dest_dir().join(&dl_key));
+        let changed = new_hash.as_ref() != manifest.output(&dl_key);
+        manifest
+            .outputs
+            .insert(dl_key, new_hash.expect("download disappeared"));
+        // upstream dates used to be kept here
+        manifest.inputs.remove(&source.url());
+        changed
+    }
+    // Whether the build directory has this exact archive unpacked in it
+    fn extracted(manifest: &Manifest, source: &HttpSource) -> bool {
+        manifest.output(&source.key()).is_some_and(|hash| {
+            manifest.output(&source.extracted_key()) == Some(&source.extraction(hash))
+        })
     }
-    pub fn git(&self, track_name: &TrackName) -> bool {
+    // With `locked`, sources are checked out at the commits in sotclk.lock instead
+    pub fn git(&self, track_name: &TrackName, locked: Option<&TrackLock>) -> bool {
         let mut out_of_date = false;
         for source in &self.git_sources {
-            let git_dir = track_name
-                .dest_dir()
-                .join("build")
-                .join(Path::new(source).file_stem().unwrap());
-
-            if !git_dir.exists() {
-                assert!(
-                    Command::new("git")
-                        .arg("clone")
-                        .arg(source)
-                        .current_dir(track_name.dest_dir().join("build"))
-                        .stdout(Stdio::inherit())
-                        .stderr(Stdio::inherit())
-                        .output()
-                        .expect("Git clone failed")
-                        .status
-                        .success(),
-                    "Git clone failed"
-                );
+            let fetched = match locked.and_then(|l| l.git.get(&source.url())) {
+                Some(commit) => source.at(commit).fetch(track_name, false),
+                None => source.fetch(track_name, self.git_update != Some(false)),
+            };
+            if fetched {
                 out_of_date = true;
-            } else {
-                if self.git_update != Some(false) {
-                    assert!(
-                        Command::new("git")
-                            .arg("remote")
-                            .arg("update")
-                            .current_dir(&git_dir)
-                            .stdout(Stdio::inherit())
-                            .stderr(Stdio::inherit())
-                            .output()
-                            .expect("Git remote failed")
-                            .status
-                            .success(),
-                        "Git remote failed"
-                    );
-                    let git_status = Command::new("git")
-                        .arg("status")
-                        .arg("-uno")
-                        .current_dir(&git_dir)
-                        .output()
-                        .expect("Git status failed");
-                    assert!(git_status.status.success(), "Git status failed");
-                    if String::from_utf8_lossy(&git_status.stdout)
-                        .lines()
-                        .nth(1)
-                        .unwrap_or("")
-                        .starts_with("Your branch is behind")
-                    {
-                        out_of_date = true;
-                        assert!(
-                            Command::new("git")
-                                .arg("reset")
-                                .arg("--hard")
-                                .current_dir(&git_dir)
-                                .stdout(Stdio::inherit())
-                                .stderr(Stdio::inherit())
-                                .output()
-                                .expect("Git reset failed")
-                                .status
-                                .success(),
-                            "Git reset failed"
-                        );
-                        assert!(
-                            Command::new("git")
-                                .arg("pull")
-                                .current_dir(&git_dir)
-                                .stdout(Stdio::inherit())
-                                .stderr(Stdio::inherit())
-                                .output()
-                                .expect("Git pull failed")
-                                .status
-                                .success(),
-                            "Git pull failed"
-                        );
-                    }
-                }
             }
         }
         out_of_date
     }
+    // Everything in the track directory, with paths relative to it
+    fn local_entries(track_name: &TrackName) -> Vec<(PathBuf, String)> {
+        WalkDir::new(track_name.source_dir())
+            .into_iter()
+            .skip(1)
+            .map(|entry| {
+                let path = entry.unwrap().into_path();
+                let relative = path
+                    .strip_prefix(track_name.source_dir())
+                    .unwrap()
+                    .display()
+                    .to_string();
+                (path, relative)
+            })
+            .collect()
+    }
+    fn local_changes(track_name: &TrackName) -> Vec<String> {
+        let manifest = Manifest::load(&track_name.dest_dir(), "local").unwrap_or_default();
+        let files: Vec<(PathBuf, String)> = Self::local_entries(track_name)
+            .into_iter()
+            .filter(|(path, _)| !path.is_dir())
+            .collect();
+        let mut changes: Vec<String> = files
+            .iter()
+            .filter(|(path, relative)| manifest.inputs.get(relative) != hash_file(path).as_ref())
+            .map(|(_, relative)| format!("local file {} changed", relative))
+            .collect();
+        changes.extend(
+            manifest
+                .inputs
+                .keys()
+                .filter(|k| !files.iter().any(|(_, relative)| relative == *k))
+                .map(|k| format!("local file {} was removed", k)),
+        );
+        changes
+    }
+    // Why the sources or build command need to run again
+    // Same checks as the build, but nothing is downloaded, fetched, or copied
+    pub fn source_changes(&self, track_name: &TrackName) -> Vec<String> {
+        let mut changes = vec![];
+        for source in &self.git_sources {
+            changes.extend(source.changes(track_name, self.git_update != Some(false)));
+        }
+        let manifest = Manifest::load(&track_name.dest_dir(), "http").unwrap_or_default();
+        for source in &self.http_sources {
+            changes.extend(self.http_check(track_name, &manifest, source));
+            if source.extract() && !Self::extracted(&manifest, source) {
+                changes.push(format!("http source {} not extracted", source));
+            }
+        }
+        if self.copy_me {
+            changes.extend(Self::local_changes(track_name));
+        }
+        if !self.build_command.is_empty() {
+            if self.always_rebuild == Some(true) {
+                changes.push("always_rebuild is set".to_owned());
+            } else if !Self::build_lock_file(track_name).exists() {
+                changes.push("build command hasn't finished".to_owned());
+            }
+        }
+        changes
+    }
     pub fn local(&self, track_name: &TrackName, cache: bool) -> bool {
+        // The manifest keeps hashes of the source files as they were last copied
+        let old_manifest = match cache {
+            true => Manifest::load(&track_name.dest_dir(), "local").unwrap_or_default(),
+            false => Manifest::default(),
+        };
+        let mut manifest = Manifest::default();
         let mut out_of_date = false;
-        for entry in WalkDir::new(track_name.source_dir()).into_iter().skip(1) {
-            let e = entry.unwrap();
-            let srcpath = e.path();
-            let srcpath_string = srcpath
-                .display()
-                .to_string()
-                .chars()
-                .skip(format!("tracks/{}/", track_name.get_name()).len()) // Path::pop would be better but ehh
-                .collect::<String>();
+        for (srcpath, srcpath_string) in Self::local_entries(track_name) {
+            let srcpath = srcpath.as_path();
             let dstpath = track_name.dest_dir().join("local").join(&srcpath_string);
 
-            let last_modified_src = if !cache {
-                None
-            } else {
-                self.get_lastmod_local(&srcpath.to_path_buf())
-            };
-            let last_modified_dst = if !cache {
-                None
-            } else {
-                self.get_lastmod_local(&dstpath)
-            };
+            if srcpath.is_dir() {
+                if !dstpath.exists() {
+                    out_of_date = true;
+                    if !command::dry_run() {
+                        std::fs::create_dir(dstpath).expect("local mkdir failed");
+                    }
+                }
+                continue;
+            }
 
-            match (last_modified_src, last_modified_dst) {
-                (Some(src), Some(dst)) if src < dst => continue,
-                _ => {}
+            let hash = hash_file(srcpath).expect("could not read local file");
+            let unchanged = old_manifest.inputs.get(&srcpath_string) == Some(&hash)
+                && hash_file(&dstpath).as_ref() == Some(&hash);
+            manifest.inputs.insert(srcpath_string.clone(), hash);
+            if unchanged {
+                continue;
             }
 
             println!("---> {}", srcpath_string);
 
             out_of_date = true;
-            if srcpath.is_dir() {
-                std::fs::create_dir(dstpath).expect("local mkdir failed");
-            } else {
-                std::fs::copy(&srcpath, dstpath).expect("local copy failed");
+            if !command::dry_run() {
+                std::fs::copy(srcpath, dstpath).expect("local copy failed");
             }
         }
+        // a file was deleted from the track directory
+        if old_manifest
+            .inputs
+            .keys()
+            .any(|k| !manifest.inputs.contains_key(k))
+        {
+            out_of_date = true;
+        }
+        manifest.save(&track_name.dest_dir(), "local");
         assert!(
-            Command::new("sh")
+            run(Command::new("sh")
                 .arg("-c")
                 .arg(format!(
                     "cp -r {}/* {}/",
@@ -319,11 +418,10 @@ impl Build {
                         .to_string_lossy()
                 ))
                 .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .output()
-                .expect("Git reset failed")
-                .status
-                .success(),
+                .stderr(Stdio::inherit()))
+            .expect("Git reset failed")
+            .status
+            .success(),
             "Git reset failed"
         );
         out_of_date
@@ -1,45 +1,288 @@
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::fs::{self, File};
-use std::io::Write;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use crate::config::TrackConfig;
+use crate::album_name::AlbumName;
+use crate::command::{self, tool_version};
 use crate::track_name::TrackName;
 
+// Anything that gets its own directory under target/
+pub trait CacheTarget: std::fmt::Display {
+    fn cache_dir(&self) -> PathBuf;
+    // Held while building, so two sotclk processes never build the same target at once
+    fn lock(&self) -> Option<fs::File> {
+        lock(
+            &self.cache_dir().join("build.lock"),
+            &format!("building '{}'", self),
+        )
+    }
+    // Held by everything that uses this target's files without building it, so they're not
+    // changed underneath them. Any number can be held at once, but not along with `lock`
+    fn lock_shared(&self) -> Option<fs::File> {
+        lock_shared(
+            &self.cache_dir().join("build.lock"),
+            &format!("building '{}'", self),
+        )
+    }
+}
+
+// Blocks until no other sotclk process holds the lock file at `path`
+// `what` says what the holder is doing. The lock is released when the file is dropped
+pub fn lock(path: &Path, what: &str) -> Option<fs::File> {
+    if command::dry_run() {
+        return None;
+    }
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let file = fs::File::create(path).unwrap();
+    if file.try_lock().is_err() {
+        println!("--> Waiting for another sotclk to finish {}", what);
+        file.lock().unwrap();
+    }
+    Some(file)
+}
+
+// Like `lock`, but shared with other holders of `lock_shared`
+pub fn lock_shared(path: &Path, what: &str) -> Option<fs::File> {
+    if command::dry_run() {
+        return None;
+    }
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .unwrap();
+    if file.try_lock_shared().is_err() {
+        println!("--> Waiting for another sotclk to finish {}", what);
+        file.lock_shared().unwrap();
+    }
+    Some(file)
+}
+
+impl CacheTarget for TrackName {
+    fn cache_dir(&self) -> PathBuf {
+        self.dest_dir()
+    }
+}
+
+impl CacheTarget for AlbumName {
+    fn cache_dir(&self) -> PathBuf {
+        self.dest_dir()
+    }
+}
+
+// What actually goes on disk, as <target dir>/<stage>.toml
+// Records written with a different schema are treated as missing
+// `tool` is the version of the program the stage ran, if any
+#[derive(Serialize, Deserialize)]
+struct CacheRecord<T> {
+    schema: u32,
+    tool: Option<String>,
+    data: T,
+}
+
+fn read_record<T: DeserializeOwned>(file: &Path) -> Result<CacheRecord<T>, String> {
+    let contents = fs::read_to_string(file).map_err(|_| "not built yet".to_owned())?;
+    toml::from_str(&contents).map_err(|_| "cache format changed".to_owned())
+}
+
+// A field that differs between a cached config and the current one
+// None means the field isn't set on that side
+pub struct FieldChange {
+    pub path: String,
+    pub old: Option<toml::Value>,
+    pub new: Option<toml::Value>,
+}
+
+// A value on one line, the way it'd be written inline in a config
+fn inline(value: &toml::Value) -> String {
+    match value {
+        toml::Value::Table(table) => format!(
+            "{{ {} }}",
+            table
+                .iter()
+                .map(|(k, v)| format!("{} = {}", k, inline(v)))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        toml::Value::Array(items) => format!(
+            "[{}]",
+            items.iter().map(inline).collect::<Vec<String>>().join(", ")
+        ),
+        value => value.to_string(),
+    }
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |v: &Option<toml::Value>| match v {
+            Some(v) => inline(v),
+            None => "(unset)".to_owned(),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+// Every field that differs between two configs, named by dotted path like "sox.sample_rate"
+// Arrays are compared item by item, as "clip[1].end"
+pub fn changed_fields(path: &str, old: &toml::Value, new: &toml::Value) -> Vec<FieldChange> {
+    let join = |key: &str| match path {
+        "" => key.to_owned(),
+        _ => format!("{}.{}", path, key),
+    };
+    match (old, new) {
+        (toml::Value::Table(old), toml::Value::Table(new)) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            keys.into_iter()
+                .flat_map(|key| match (old.get(key), new.get(key)) {
+                    (Some(o), Some(n)) => changed_fields(&join(key), o, n),
+                    (o, n) => vec![FieldChange {
+                        path: join(key),
+                        old: o.cloned(),
+                        new: n.cloned(),
+                    }],
+                })
+                .collect()
+        }
+        (toml::Value::Array(old), toml::Value::Array(new)) if old.len() == new.len() => old
+            .iter()
+            .zip(new)
+            .enumerate()
+            .flat_map(|(i, (o, n))| changed_fields(&format!("{}[{}]", path, i), o, n))
+            .collect(),
+        (old, new) if old == new => vec![],
+        (old, new) => vec![FieldChange {
+            path: path.to_owned(),
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }],
+    }
+}
+
 pub trait Cache {
-    fn load_from_cache(track_name: &TrackName) -> Option<Self>
+    fn write_cache(&self, target: &impl CacheTarget)
     where
-        Self: Sized + From<TrackConfig> + DeserializeOwned + std::fmt::Debug,
+        Self: Serialize + Sized,
     {
-        let filename = track_name
-            .dest_dir()
-            .join(format!("{}.toml", Self::self_type()));
-        fs::read_to_string(&filename)
-            .ok()
-            .and_then(|cfg_str| toml::from_str::<Self>(&cfg_str).ok())
-    }
-    fn write_cache(&self, track_name: &TrackName)
+        if command::dry_run() {
+            return;
+        }
+        // going through Value puts plain values before tables, which toml insists on
+        let record = toml::Value::try_from(CacheRecord {
+            schema: Self::schema(),
+            tool: Self::tool().map(tool_version),
+            data: self,
+        })
+        .unwrap();
+        fs::write(Self::cache_file(target), toml::to_string(&record).unwrap()).unwrap();
+    }
+    // Why the cached record doesn't match this one, if it doesn't
+    fn stale_reason(&self, target: &impl CacheTarget) -> Option<String>
     where
-        Self: Serialize,
+        Self: Sized + Serialize + DeserializeOwned + PartialEq,
     {
-        let mut current_sox_config_str = format!(
-            "{}",
-            toml::to_string(&self)
-                .unwrap()
-                .replace("[[]]", &format!("[[{}]]", Self::self_type()))
-        ); // for vector types
-
-        if current_sox_config_str.as_str() == "[]" {
-            current_sox_config_str = "".to_owned();
+        let record = match read_record::<Self>(&Self::cache_file(target)) {
+            Ok(record) => record,
+            Err(reason) => return Some(reason),
+        };
+        let tool = Self::tool().map(tool_version);
+        if record.schema != Self::schema() {
+            Some("cache format changed".to_owned())
+        } else if record.tool != tool {
+            let tool_name = Self::tool().unwrap_or_default();
+            Some(match record.tool {
+                Some(old) => format!(
+                    "{} changed from '{}' to '{}'",
+                    tool_name,
+                    old,
+                    tool.unwrap_or_default()
+                ),
+                None => format!("no {} version was recorded", tool_name),
+            })
+        } else if &record.data != self {
+            let fields: Vec<String> = Self::field_changes(&record.data, self)
+                .into_iter()
+                .map(|c| c.path)
+                .collect();
+            Some(match fields.is_empty() {
+                true => format!("[{}] config changed", Self::self_type()),
+                false => format!("{} changed", fields.join(", ")),
+            })
+        } else {
+            None
         }
-
-        let mut file = File::create(
-            track_name
-                .dest_dir()
-                .join(format!("{}.toml", Self::self_type())),
+    }
+    // Everything that differs from the cached record, one line each,
+    // with old and new values for config fields
+    fn changes(&self, target: &impl CacheTarget) -> Vec<String>
+    where
+        Self: Sized + Serialize + DeserializeOwned + PartialEq,
+    {
+        let record = match read_record::<Self>(&Self::cache_file(target)) {
+            Ok(record) if record.schema == Self::schema() => record,
+            Ok(_) => return vec!["cache format changed".to_owned()],
+            Err(reason) => return vec![reason],
+        };
+        let mut changes = vec![];
+        let tool = Self::tool().map(tool_version);
+        if record.tool != tool {
+            changes.push(format!(
+                "{}: {} -> {}",
+                Self::tool().unwrap_or_default(),
+                record.tool.unwrap_or_else(|| "(unrecorded)".to_owned()),
+                tool.unwrap_or_default()
+            ));
+        }
+        changes.extend(
+            Self::field_changes(&record.data, self)
+                .iter()
+                .map(|c| c.to_string()),
+        );
+        changes
+    }
+    // The tool version the cached record was made with
+    fn recorded_tool(target: &impl CacheTarget) -> Option<String>
+    where
+        Self: Sized + DeserializeOwned,
+    {
+        read_record::<Self>(&Self::cache_file(target)).ok()?.tool
+    }
+    fn field_changes(old: &Self, new: &Self) -> Vec<FieldChange>
+    where
+        Self: Sized + Serialize,
+    {
+        changed_fields(
+            Self::config_path(),
+            &toml::Value::try_from(old).unwrap(),
+            &toml::Value::try_from(new).unwrap(),
         )
-        .unwrap();
-        file.write_all(current_sox_config_str.as_bytes()).unwrap();
     }
+    fn cache_file(target: &impl CacheTarget) -> PathBuf {
+        target
+            .cache_dir()
+            .join(format!("{}.toml", Self::self_type()))
+    }
+    // the stage this record belongs to
     fn self_type() -> &'static str;
+    // where this record's fields live in the config file, for naming changed fields
+    fn config_path() -> &'static str {
+        Self::self_type()
+    }
+    // bump when the cached type changes shape
+    fn schema() -> u32 {
+        1
+    }
+    // the external program this stage runs, if it affects the output
+    fn tool() -> Option<&'static str> {
+        None
+    }
 }
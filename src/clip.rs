@@ -1,10 +1,13 @@
 use chrono::naive::NaiveTime;
+use chrono::Duration;
 use serde::{Deserialize, Serialize};
 use std::process::{Command, Stdio};
 
 use crate::cache::Cache;
+use crate::command::run;
 use crate::config::TrackConfig;
 use crate::config::TrackData;
+use crate::manifest::{commit_file, partial_file};
 use crate::track_name::TrackName;
 
 pub type Clips = Vec<Clip>;
@@ -23,10 +26,67 @@ pub struct Clip {
 
 pub trait ClipProcess {
     fn process(self, track_name: &TrackName, tempo: f64);
+    fn absolute(self) -> Self;
+    fn runtime(self, available: Duration) -> Duration;
 }
 
 impl ClipProcess for Clips {
-    fn process(mut self, track_name: &TrackName, mut tempo: f64) {
+    // Converts every clip to an absolute position, checking for bad positions
+    fn absolute(mut self) -> Self {
+        // check clips for "absolute"/"relative" correctness
+        for clip in self.iter() {
+            match clip.position.as_str() {
+                "absolute" | "relative" => {}
+                other => panic!(
+                    "clip position '{}' is invalid. \
+				     Valid options are: relative, absolute.",
+                    other
+                ),
+            }
+        }
+
+        // logically, the first clip is always absolute
+        // However, if it is relative, make sure the end is adjusted
+        if &self[0].position == "relative" {
+            let first = &mut self[0];
+            first.position = "absolute".to_owned();
+            first.end += first
+                .start
+                .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+        }
+
+        // now, fold through and make everything absolute
+        for clip_n in 1..self.len() {
+            if &self[clip_n].position == "relative" {
+                let offset = self[clip_n - 1]
+                    .end
+                    .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+                self[clip_n].start += offset;
+                let start_time = self[clip_n]
+                    .start
+                    .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+                self[clip_n].end += start_time;
+                self[clip_n].position = "absolute".to_owned();
+            }
+        }
+        self
+    }
+    // How much audio is left after clipping `available` worth of audio
+    fn runtime(self, available: Duration) -> Duration {
+        if self.is_empty() {
+            return available;
+        }
+        let zero = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+        self.absolute()
+            .into_iter()
+            .map(|clip| {
+                let start = clip.start.signed_duration_since(zero).min(available);
+                let end = clip.end.signed_duration_since(zero).min(available);
+                (end - start).max(Duration::zero())
+            })
+            .fold(Duration::zero(), |acc, d| acc + d)
+    }
+    fn process(self, track_name: &TrackName, mut tempo: f64) {
         let mut tempo_modifiers = vec![];
         while tempo > 2.0 {
             tempo_modifiers.push(2.0);
@@ -44,8 +104,9 @@ impl ClipProcess for Clips {
             .collect::<Vec<String>>()
             .join(",");
 
-        std::fs::remove_file(track_name.dest_dir().join(TrackData::processed_filename())).ok();
-        if self.len() == 0 {
+        let processed = track_name.dest_dir().join(TrackData::processed_filename());
+        let output = partial_file(&processed);
+        if self.is_empty() {
             // Re-encoding fixes any potential errors that sox may encounter
             // It's pretty fast for flac anyway
             // https://gist.github.com/jgehrcke/5572c50bedf998a1fae40a80afa80357#file-flac-reencode-py-L24-L29
@@ -58,15 +119,10 @@ impl ClipProcess for Clips {
                     .into_os_string()
                     .to_string_lossy(),
                 tempo_arg,
-                track_name
-                    .dest_dir()
-                    .join(TrackData::processed_filename())
-                    .clone()
-                    .into_os_string()
-                    .to_string_lossy()
+                output.display()
             );
             assert!(
-                Command::new("ffmpeg")
+                run(Command::new("ffmpeg")
                     .arg("-i")
                     .arg(
                         track_name
@@ -75,67 +131,32 @@ impl ClipProcess for Clips {
                     )
                     .arg("-filter:a")
                     .arg(tempo_arg)
-                    .arg(track_name.dest_dir().join(TrackData::processed_filename()))
+                    .arg(&output)
                     .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .expect("ffmpeg failed. Aborting.")
-                    .status
-                    .success(),
+                    .stderr(Stdio::inherit()))
+                .expect("ffmpeg failed. Aborting.")
+                .status
+                .success(),
                 "ffmpeg failed. Aborting."
             );
         } else {
             println!("--> Editing with ffmpeg");
 
-            // check clips for "absolute"/"relative" correctness
-            for clip in self.iter() {
-                match clip.position.as_str() {
-                    "absolute" | "relative" => {}
-                    other => panic!(
-                        "clip position '{}' is invalid. \
-				     Valid options are: relative, absolute.",
-                        other
-                    ),
-                }
-            }
-
-            // logically, the first clip is always absolute
-            // However, if it is relative, make sure the end is adjusted
-            if &self[0].position == "relative" {
-                let mut first = &mut self[0];
-                first.position = "absolute".to_owned();
-                first.end += first
-                    .start
-                    .signed_duration_since(NaiveTime::from_hms(0, 0, 0));
-            }
-
-            // now, fold through and make everything absolute
-            for clip_n in 1..self.len() {
-                if &self[clip_n].position == "relative" {
-                    let offset = self[clip_n - 1]
-                        .end
-                        .signed_duration_since(NaiveTime::from_hms(0, 0, 0));
-                    self[clip_n].start += offset;
-                    let start_time = self[clip_n]
-                        .start
-                        .signed_duration_since(NaiveTime::from_hms(0, 0, 0));
-                    self[clip_n].end += start_time;
-                    self[clip_n].position = "absolute".to_owned();
-                }
-            }
+            let clips = self.absolute();
 
             let filter_arg = format!(
                 "{},aselect='{}',asetpts=N/SR/TB",
                 tempo_arg,
-                self.into_iter()
+                clips
+                    .into_iter()
                     .map(|clip| {
                         format!(
                             "between(t,{},{})",
                             clip.start
-                                .signed_duration_since(NaiveTime::from_hms(0, 0, 0))
+                                .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
                                 .num_seconds(),
                             clip.end
-                                .signed_duration_since(NaiveTime::from_hms(0, 0, 0))
+                                .signed_duration_since(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
                                 .num_seconds()
                         )
                     })
@@ -152,16 +173,11 @@ impl ClipProcess for Clips {
                     .into_os_string()
                     .to_string_lossy(),
                 filter_arg,
-                track_name
-                    .dest_dir()
-                    .join(TrackData::processed_filename())
-                    .clone()
-                    .into_os_string()
-                    .to_string_lossy()
+                output.display()
             );
 
             assert!(
-                Command::new("ffmpeg")
+                run(Command::new("ffmpeg")
                     .arg("-i")
                     .arg(
                         track_name
@@ -170,16 +186,16 @@ impl ClipProcess for Clips {
                     )
                     .arg("-af")
                     .arg(filter_arg)
-                    .arg(track_name.dest_dir().join(TrackData::processed_filename()))
+                    .arg(&output)
                     .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .expect("ffmpeg failed. Aborting.")
-                    .status
-                    .success(),
+                    .stderr(Stdio::inherit()))
+                .expect("ffmpeg failed. Aborting.")
+                .status
+                .success(),
                 "ffmpeg failed. Aborting."
             );
         }
+        commit_file(&processed);
     }
 }
 
@@ -195,14 +211,15 @@ impl From<TrackConfig> for ClipsOpt {
     }
 }
 
-impl Cache for Clips {
-    fn self_type() -> &'static str {
-        "clip"
-    }
-}
-
 impl Cache for ClipsOpt {
     fn self_type() -> &'static str {
         "clip"
     }
+    // [[clip]] is already top level
+    fn config_path() -> &'static str {
+        ""
+    }
+    fn tool() -> Option<&'static str> {
+        Some("ffmpeg")
+    }
 }
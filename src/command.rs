@@ -0,0 +1,259 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+// What's going on right now, so an interrupt knows what to stop and clean up
+struct Current {
+    stage: Option<String>,
+    groups: Vec<i32>, // process groups of running commands
+    partials: Vec<PathBuf>,
+    signal: Option<i32>,
+}
+
+static CURRENT: Mutex<Current> = Mutex::new(Current {
+    stage: None,
+    groups: vec![],
+    partials: vec![],
+    signal: None,
+});
+
+// Catches SIGINT and SIGTERM for the rest of the program
+// If commands are running, their whole process groups get the signal and `run` cleans up
+// once they exit. Otherwise cleanup happens straight away
+pub fn handle_signals() {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("could not set up signal handling");
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let mut current = CURRENT.lock().unwrap();
+            if current.signal.is_some() {
+                continue; // already stopping
+            }
+            current.signal = Some(signal);
+            if current.groups.is_empty() {
+                interrupted(current);
+            }
+            let groups = current.groups.clone();
+            drop(current);
+            for group in &groups {
+                unsafe {
+                    libc::kill(-group, signal);
+                }
+            }
+            stop_groups(&groups);
+        }
+    });
+}
+
+// Gives stragglers that ignored the signal a few seconds before killing them
+fn stop_groups(groups: &[i32]) {
+    let alive = || {
+        groups
+            .iter()
+            .filter(|g| unsafe { libc::kill(-**g, 0) } == 0)
+            .copied()
+            .collect::<Vec<i32>>()
+    };
+    for _ in 0..50 {
+        if alive().is_empty() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    for group in alive() {
+        unsafe {
+            libc::kill(-group, libc::SIGKILL);
+        }
+    }
+}
+
+// Lets go of CURRENT before cleaning up, so nothing else waits on it while the process exits
+fn interrupted(current: MutexGuard<Current>) -> ! {
+    let partials = current.partials.clone();
+    let stage = current.stage.clone();
+    let signal = current.signal.unwrap_or(SIGINT);
+    drop(current);
+    for partial in &partials {
+        fs::remove_file(partial).ok();
+    }
+    eprintln!("Interrupted during {}", stage.as_deref().unwrap_or("setup"));
+    std::process::exit(128 + signal);
+}
+
+// Whether sotclk is what the terminal is reading for, so a command can be handed the terminal
+fn in_foreground() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::tcgetpgrp(0) == libc::getpgrp() }
+}
+
+// Makes `group` the terminal's foreground, so commands in their own process group can still
+// read from and set up the terminal (git asking for a password, say) without being stopped
+// SIGTTOU is blocked meanwhile, since sotclk is in the background when it takes the terminal back
+fn give_terminal(group: i32) {
+    unsafe {
+        let mut ttou: libc::sigset_t = std::mem::zeroed();
+        let mut old: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut ttou);
+        libc::sigaddset(&mut ttou, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &ttou, &mut old);
+        libc::tcsetpgrp(0, group);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+    }
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+// Set by --dry-run: `run` only prints what it would run, and nothing under target/ is written
+pub fn set_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// Shown if the build is interrupted
+pub fn set_stage(stage: String) {
+    CURRENT.lock().unwrap().stage = Some(stage);
+}
+
+// Files to remove if the build is interrupted
+pub fn add_partial(path: &Path) {
+    CURRENT.lock().unwrap().partials.push(path.to_path_buf());
+}
+
+pub fn remove_partial(path: &Path) {
+    CURRENT.lock().unwrap().partials.retain(|p| p != path);
+}
+
+// First line of `tool --version`, looked up once per run
+pub fn tool_version(tool: &str) -> String {
+    static VERSIONS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+    VERSIONS
+        .lock()
+        .unwrap()
+        .entry(tool.to_owned())
+        .or_insert_with(|| {
+            let flag = match tool {
+                "ffmpeg" | "ffprobe" => "-version", // ffmpeg doesn't do double dashes
+                _ => "--version",
+            };
+            Command::new(tool)
+                .arg(flag)
+                .output()
+                .ok()
+                .map(|o| {
+                    let stdout = String::from_utf8_lossy(&o.stdout).to_string();
+                    let line = stdout.lines().next().unwrap_or("").to_owned();
+                    // ffmpeg puts its copyright on the same line
+                    let line = match line.find(" Copyright") {
+                        Some(i) => &line[..i],
+                        None => &line,
+                    };
+                    line.split_whitespace().collect::<Vec<&str>>().join(" ")
+                })
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "unknown".to_owned())
+        })
+        .clone()
+}
+
+// Runs a command in its own process group, so an interrupt stops everything it started
+// Stdio isn't changed; set stdout to piped to capture it like Command::output does
+// In a dry run, it's printed along with where it would run, and "succeeds" without running
+pub fn run(cmd: &mut Command) -> io::Result<Output> {
+    if dry_run() {
+        let cwd = match cmd.get_current_dir() {
+            Some(dir) => dir.to_path_buf(),
+            None => std::env::current_dir()?,
+        };
+        let argv: Vec<&std::ffi::OsStr> = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .collect();
+        println!("---> would run in {}: {:?}", cwd.display(), argv);
+        return Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: vec![],
+            stderr: vec![],
+        });
+    }
+    cmd.process_group(0);
+    let foreground = in_foreground();
+    let child = started(cmd)?;
+    let group = child.id() as i32;
+    if foreground {
+        give_terminal(group);
+        unsafe {
+            libc::kill(-group, libc::SIGCONT); // in case it read from the terminal too early
+        }
+    }
+    let output = child.wait_with_output();
+    let mut signal = None;
+    if foreground {
+        give_terminal(unsafe { libc::getpgrp() });
+        // a Ctrl-C only reached the command, since it had the terminal
+        signal = output
+            .as_ref()
+            .ok()
+            .and_then(|o| o.status.signal())
+            .filter(|s| *s == SIGINT);
+    }
+    finished(group, signal);
+    output
+}
+
+fn started(cmd: &mut Command) -> io::Result<Child> {
+    let mut current = CURRENT.lock().unwrap();
+    if current.signal.is_some() {
+        interrupted(current);
+    }
+    let child = cmd.spawn()?;
+    current.groups.push(child.id() as i32);
+    Ok(child)
+}
+
+fn finished(group: i32, signal: Option<i32>) {
+    let mut current = CURRENT.lock().unwrap();
+    current.groups.retain(|g| *g != group);
+    if current.signal.is_none() {
+        current.signal = signal;
+    }
+    if current.signal.is_some() {
+        interrupted(current);
+    }
+}
+
+// Like `run`, but for commands running side by side: output is passed on line by line,
+// each line starting with `prefix`. Not affected by --dry-run
+pub fn run_prefixed(cmd: &mut Command, prefix: &str) -> io::Result<ExitStatus> {
+    // side by side, none of them can have the terminal
+    cmd.process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = started(cmd)?;
+    let group = child.id() as i32;
+    let stderr = child.stderr.take().unwrap();
+    let stderr_prefix = prefix.to_owned();
+    let stderr_thread = std::thread::spawn(move || {
+        for line in BufReader::new(stderr).split(b'\n').map_while(Result::ok) {
+            eprintln!("{} {}", stderr_prefix, String::from_utf8_lossy(&line));
+        }
+    });
+    for line in BufReader::new(child.stdout.take().unwrap())
+        .split(b'\n')
+        .map_while(Result::ok)
+    {
+        println!("{} {}", prefix, String::from_utf8_lossy(&line));
+    }
+    stderr_thread.join().ok();
+    let status = child.wait();
+    finished(group, None);
+    status
+}
@@ -1,11 +1,16 @@
+use chrono::Duration;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
 use crate::build::Build;
 use crate::cache::Cache;
-use crate::clip::{Clips, ClipsOpt};
+use crate::clip::{ClipProcess, Clips, ClipsOpt};
+use crate::command::{self, run};
+use crate::manifest::{commit_file, fingerprint, hash_file, partial_file, Manifest};
 use crate::track_name::TrackName;
 
 #[derive(Clone, Debug)]
@@ -14,23 +19,242 @@ pub struct TrackData {
     pub updates: Updates,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Updates {
     pub needs_raw_update: bool,
     pub needs_preprocessed_update: bool,
     pub needs_build_update: bool,
     pub needs_ffmpeg_update: bool,
+    pub reasons: Vec<(Stage, String)>,
+}
+
+// The steps a track goes through, in order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Build,
+    Raw,
+    Sox,
+    Clip,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Build => "build",
+            Self::Raw => "raw",
+            Self::Sox => "sox",
+            Self::Clip => "clip",
+        }
+    }
+    fn previous(self) -> Option<Self> {
+        match self {
+            Self::Build => None,
+            Self::Raw => Some(Self::Build),
+            Self::Sox => Some(Self::Raw),
+            Self::Clip => Some(Self::Sox),
+        }
+    }
+    pub fn next(self) -> Option<Self> {
+        match self {
+            Self::Build => Some(Self::Raw),
+            Self::Raw => Some(Self::Sox),
+            Self::Sox => Some(Self::Clip),
+            Self::Clip => None,
+        }
+    }
+    fn cache_file(self, track_name: &TrackName) -> PathBuf {
+        match self {
+            Self::Build => Build::cache_file(track_name),
+            Self::Raw => Output::cache_file(track_name),
+            Self::Sox => Sox::cache_file(track_name),
+            Self::Clip => ClipsOpt::cache_file(track_name),
+        }
+    }
+    // The file this stage leaves in the track's target directory
+    fn output_file(self) -> Option<&'static str> {
+        match self {
+            Self::Build => None,
+            Self::Raw => Some(TrackData::raw_filename()),
+            Self::Sox => Some(TrackData::unprocessed_filename()),
+            Self::Clip => Some(TrackData::processed_filename()),
+        }
+    }
+    fn manifest(self, track_name: &TrackName) -> Option<Manifest> {
+        Manifest::load(&track_name.dest_dir(), self.name())
+    }
+    // Hash of this stage's output, as of the last time it ran
+    pub fn output_hash(self, track_name: &TrackName) -> Option<String> {
+        self.manifest(track_name)
+            .and_then(|m| m.output(self.output_file()?).cloned())
+    }
+    // For `sotclk status`: "up to date", or "stale"/"missing" along with why
+    // Missing means the stage never finished, or its output is gone
+    pub fn status<'a>(
+        self,
+        track_name: &TrackName,
+        updates: &'a Updates,
+    ) -> (&'static str, &'a str) {
+        let output_missing = self
+            .output_file()
+            .map(|f| !track_name.dest_dir().join(f).exists())
+            .unwrap_or(false);
+        match updates.reason(self) {
+            None => ("up to date", ""),
+            Some(reason) if output_missing || !self.cache_file(track_name).exists() => {
+                ("missing", reason)
+            }
+            Some(reason) => ("stale", reason),
+        }
+    }
+    // Forgets this stage and everything after it, so a crash part way through
+    // can't leave records claiming the old outputs are up to date
+    pub fn begin(self, track_name: &TrackName, updates: &Updates) {
+        if let Some(reason) = updates.reason(self) {
+            println!("--> Running {} stage: {}", self.name(), reason);
+        }
+        command::set_stage(format!("{} stage of track '{}'", self.name(), track_name));
+        let mut stage = Some(self);
+        while let Some(s) = stage {
+            if !command::dry_run() {
+                fs::remove_file(s.cache_file(track_name)).ok();
+            }
+            Manifest::remove(&track_name.dest_dir(), s.name());
+            stage = s.next();
+        }
+    }
+    // Takes the stage's record out while what it reads is being updated, so an interrupt
+    // part way through leaves the stage stale instead of up to date with new inputs
+    pub fn suspend(self, track_name: &TrackName) -> Option<Vec<u8>> {
+        if command::dry_run() {
+            return None;
+        }
+        let file = self.cache_file(track_name);
+        let record = fs::read(&file).ok();
+        fs::remove_file(file).ok();
+        record
+    }
+    // Puts the record back once that turned out not to change anything
+    pub fn resume(self, track_name: &TrackName, record: Option<Vec<u8>>) {
+        if let Some(record) = record {
+            fs::write(self.cache_file(track_name), record).unwrap();
+        }
+    }
+    // Called once the stage has run
+    pub fn record(self, track_name: &TrackName, track_config: &TrackConfig) {
+        let dir = track_name.dest_dir();
+        let mut manifest = Manifest::default();
+        if self == Self::Raw {
+            manifest.inputs = track_config.input_hashes(track_name);
+        }
+        if let Some(prev) = self.previous() {
+            if let (Some(file), Some(hash)) = (prev.output_file(), prev.output_hash(track_name)) {
+                manifest.inputs.insert(file.to_owned(), hash);
+            }
+        }
+        if let Some(file) = self.output_file() {
+            manifest.hash_outputs(&dir, &[file]);
+        }
+        manifest.save(&dir, self.name());
+    }
+    // None if the output is untouched and was made from what the previous stage has now
+    fn stale_reason(self, track_name: &TrackName, track_config: &TrackConfig) -> Option<String> {
+        self.file_changes(track_name, track_config)
+            .into_iter()
+            .next()
+    }
+    // Every input and output that differs from what this stage last read and wrote
+    fn file_changes(self, track_name: &TrackName, track_config: &TrackConfig) -> Vec<String> {
+        let manifest = match self.manifest(track_name) {
+            Some(manifest) => manifest,
+            None => return vec!["not built yet".to_owned()],
+        };
+        let mut changes = vec![];
+        if self == Self::Raw {
+            let current = track_config.input_hashes(track_name);
+            let files: BTreeSet<&String> = current.keys().chain(manifest.inputs.keys()).collect();
+            for file in files {
+                if current.get(file) != manifest.inputs.get(file) {
+                    changes.push(format!("input {} changed", file));
+                }
+            }
+        }
+        if let Some(prev) = self.previous() {
+            if let Some(file) = prev.output_file() {
+                if manifest.inputs.get(file) != prev.output_hash(track_name).as_ref() {
+                    changes.push(format!("{} changed", file));
+                }
+            }
+        }
+        if manifest.outputs.is_empty() {
+            changes.push(format!(
+                "{} missing",
+                self.output_file().unwrap_or("output")
+            ));
+        }
+        changes.extend(manifest.damaged_output(&track_name.dest_dir()));
+        changes
+    }
+    // Everything that makes this stage stale: config fields with their old and new values,
+    // then changed sources, inputs, and files. Only reads, like `sotclk status`
+    pub fn explain(self, track_name: &TrackName, track_config: &TrackConfig) -> Vec<String> {
+        match self {
+            Self::Build => match &track_config.build {
+                Some(build) => {
+                    let mut changes = build.cached().changes(track_name);
+                    changes.extend(build.source_changes(track_name));
+                    changes
+                }
+                None => vec![],
+            },
+            Self::Raw => {
+                let mut changes = track_config.output.changes(track_name);
+                changes.extend(self.file_changes(track_name, track_config));
+                changes
+            }
+            Self::Sox => {
+                let mut changes = track_config.sox.changes(track_name);
+                changes.extend(self.file_changes(track_name, track_config));
+                changes
+            }
+            Self::Clip => {
+                let mut changes = ClipsOpt::from(track_config.clone()).changes(track_name);
+                changes.extend(self.file_changes(track_name, track_config));
+                changes
+            }
+        }
+    }
 }
 
 impl Updates {
+    // Marks a stage, and everything after it, as needing to run
+    pub fn invalidate(&mut self, stage: Stage, reason: String) {
+        let flag = match stage {
+            Stage::Build => &mut self.needs_build_update,
+            Stage::Raw => &mut self.needs_raw_update,
+            Stage::Sox => &mut self.needs_preprocessed_update,
+            Stage::Clip => &mut self.needs_ffmpeg_update,
+        };
+        if *flag {
+            return;
+        }
+        *flag = true;
+        self.reasons.push((stage, reason));
+        if let Some(next) = stage.next() {
+            self.invalidate(next, format!("{} stage is rerunning", stage.name()));
+        }
+    }
+    // Why a stage needs to run, if it does
+    pub fn reason(&self, stage: Stage) -> Option<&str> {
+        self.reasons
+            .iter()
+            .find(|(s, _)| *s == stage)
+            .map(|(_, r)| r.as_str())
+    }
     pub fn build_updated(&mut self) {
-        self.needs_build_update = true;
-        self.needs_raw_update = true;
-        self.needs_preprocessed_update = true;
+        self.invalidate(Stage::Build, "sources changed".to_owned());
     }
     pub fn rebuilt(&mut self) {
-        self.needs_raw_update = true;
-        self.needs_preprocessed_update = true;
+        self.invalidate(Stage::Raw, "build command ran".to_owned());
     }
 }
 
@@ -40,6 +264,117 @@ pub struct TrackConfig {
     pub sox: Sox,
     pub build: Option<Build>,
     pub clip: Option<Clips>,
+    pub info: Option<Info>,
+}
+
+// [info] is never part of a cache; changing it only touches albums
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Info {
+    pub credits: Option<String>,
+}
+
+impl TrackConfig {
+    // Just the config file, without checking what's stale
+    pub fn load(track_name: &TrackName) -> Self {
+        let filename = track_name.source_dir().join("config.toml");
+        let config: Self = toml::from_str(
+            &fs::read_to_string(filename.clone())
+                .unwrap_or_else(|_| panic!("could not read file {}", filename.display())),
+        )
+        .unwrap();
+        if let Some(build) = &config.build {
+            if let Some(shared) = &build.reuse_build_dir {
+                assert!(
+                    build.build_command.is_empty()
+                        && build.http_sources.is_empty()
+                        && build.git_sources.is_empty()
+                        && !build.copy_me,
+                    "Track '{}' reuses the build directory of '{}', \
+                     so it can't have sources or a build_command of its own",
+                    track_name,
+                    shared
+                );
+            }
+        }
+        config
+    }
+    // Tracks that have to be built before this one
+    pub fn dependencies(&self) -> Vec<String> {
+        self.build
+            .as_ref()
+            .map(|b| b.dependencies())
+            .unwrap_or_default()
+    }
+    // How many of build-all's job slots this track takes up
+    pub fn weight(&self, slots: usize) -> usize {
+        match &self.build {
+            Some(build) if build.exclusive == Some(true) => slots,
+            Some(build) => build.weight.unwrap_or(1).clamp(1, slots),
+            None => 1,
+        }
+    }
+    // Where output_command runs
+    fn output_dir(&self, track_name: &TrackName) -> PathBuf {
+        match &self.build {
+            Some(Build {
+                reuse_build_dir: Some(shared),
+                ..
+            }) => track_name.sibling(shared).build_dir(),
+            Some(_) => track_name.build_dir(),
+            None => PathBuf::from("."),
+        }
+    }
+    // Content hashes of the files named in [output] inputs
+    // Files matched by a glob only get a size and modification time, since a pattern like
+    // /var/log/journal/**/* can match more than is worth hashing on every run
+    // Patterns that match nothing are kept too, so files showing up count as a change
+    pub fn input_hashes(&self, track_name: &TrackName) -> BTreeMap<String, String> {
+        let mut hashes = BTreeMap::new();
+        for pattern in self.output.inputs.iter().flatten() {
+            let full_pattern = self.output_dir(track_name).join(pattern);
+            let is_glob = glob::Pattern::escape(pattern) != *pattern;
+            let (mut paths, mut unreadable) = (vec![], false);
+            for path in glob::glob(&full_pattern.to_string_lossy())
+                .unwrap_or_else(|e| panic!("invalid input pattern '{}': {}", pattern, e))
+            {
+                match path {
+                    Ok(path) if !path.is_file() => {}
+                    Ok(path) => paths.push(path),
+                    Err(e) => {
+                        println!("---> warning: input {} can't be read", e.path().display());
+                        hashes.insert(e.path().display().to_string(), "unreadable".to_owned());
+                        unreadable = true;
+                    }
+                }
+            }
+            if paths.is_empty() && !unreadable {
+                hashes.insert(pattern.clone(), "no matches".to_owned());
+            }
+            for path in paths {
+                let hash = match is_glob {
+                    true => fingerprint(&path),
+                    false => hash_file(&path),
+                };
+                let hash = hash.unwrap_or_else(|| {
+                    println!("---> warning: input {} can't be read", path.display());
+                    "unreadable".to_owned()
+                });
+                hashes.insert(path.display().to_string(), hash);
+            }
+        }
+        // a shared build directory counts as changed whenever what it was made from changes
+        if let Some(shared) = self.build.as_ref().and_then(|b| b.reuse_build_dir.as_ref()) {
+            let shared_name = track_name.sibling(shared);
+            hashes.insert(
+                format!("build directory of track '{}'", shared),
+                match TrackConfig::load(&shared_name).build {
+                    Some(build) => build.source_state(&shared_name),
+                    None => "not built".to_owned(),
+                },
+            );
+        }
+        hashes
+    }
 }
 
 impl TrackData {
@@ -53,66 +388,51 @@ impl TrackData {
         "processed.flac"
     }
     pub fn load_from_track(track_name: &TrackName) -> Self {
-        let filename = track_name.source_dir().join("config.toml");
-        let track_config: TrackConfig = toml::from_str(
-            &fs::read_to_string(filename.clone())
-                .expect(&format!("could not read file {}", filename.display())),
-        )
-        .unwrap();
+        let track_config = TrackConfig::load(track_name);
 
-        let needs_build_update = match (track_config.output.cache, &track_config.build) {
-            (Some(false), _) => true, // will propogate
-            (_, Some(bref)) => Build::load_from_cache(&track_name) != Some(bref.clone()),
-            _ => false,
+        let mut updates = Updates::default();
+        let build_reason = match (track_config.output.cache, &track_config.build) {
+            (Some(false), Some(_)) => Some("caching is off".to_owned()), // will propogate
+            (_, Some(bref)) => bref.cached().stale_reason(track_name),
+            _ => None,
         };
-
-        let needs_raw_update = match needs_build_update {
-            true => true,
-            false => {
-                (Output::load_from_cache(&track_name) != Some(track_config.clone().into()))
-                    || !track_name
-                        .dest_dir()
-                        .join(TrackData::raw_filename())
-                        .exists()
-            }
-        };
-
-        let needs_preprocessed_update = match needs_raw_update {
-            true => true,
-            false => {
-                (Sox::load_from_cache(&track_name) != Some(track_config.clone().into()))
-                    || !track_name
-                        .dest_dir()
-                        .join(TrackData::unprocessed_filename())
-                        .exists()
-            }
-        };
-
-        let needs_ffmpeg_update = match needs_preprocessed_update {
-            true => true,
-            false => {
-                (ClipsOpt::load_from_cache(&track_name) != Some(track_config.clone().into()))
-                    || !track_name
-                        .dest_dir()
-                        .join(TrackData::processed_filename())
-                        .exists()
+        if let Some(reason) = build_reason {
+            updates.invalidate(Stage::Build, reason);
+        }
+        let stages: [(Stage, Option<String>); 3] = [
+            (Stage::Raw, track_config.output.stale_reason(track_name)),
+            (Stage::Sox, track_config.sox.stale_reason(track_name)),
+            (
+                Stage::Clip,
+                ClipsOpt::from(track_config.clone()).stale_reason(track_name),
+            ),
+        ];
+        for (stage, config_reason) in stages {
+            if let Some(reason) =
+                config_reason.or_else(|| stage.stale_reason(track_name, &track_config))
+            {
+                updates.invalidate(stage, reason);
             }
-        };
+        }
 
         Self {
             track_config,
-            updates: Updates {
-                needs_raw_update,
-                needs_preprocessed_update,
-                needs_build_update,
-                needs_ffmpeg_update,
-            },
+            updates,
+        }
+    }
+    // Counts changed sources as making the build stale too, without fetching anything
+    pub fn check_sources(&mut self, track_name: &TrackName) {
+        if let Some(build_cfg) = &self.track_config.build {
+            if !self.updates.needs_build_update {
+                if let Some(reason) = build_cfg.source_changes(track_name).into_iter().next() {
+                    self.updates.invalidate(Stage::Build, reason);
+                }
+            }
         }
     }
     pub fn dump_raw(&self, track_name: &TrackName) {
         let intermed_file = track_name.dest_dir().join(TrackData::raw_filename());
-
-        std::fs::remove_file(&intermed_file).ok(); // makes cache happy
+        let partial = partial_file(&intermed_file);
 
         let mut cmd = Command::new("sh");
         cmd.arg("-c").arg(
@@ -123,15 +443,22 @@ impl TrackData {
 
         if self.output().debug == Some(true) {
             cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+        } else {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
         }
         if self.build().is_some() {
-            cmd.current_dir(track_name.dest_dir().join("build"));
+            cmd.current_dir(self.track_config.output_dir(track_name));
         }
 
-        let output = cmd.output().expect("Output command failed");
+        let output = run(&mut cmd).expect("Output command failed");
+        if command::dry_run() {
+            return;
+        }
 
-        let mut file = File::create(&intermed_file).unwrap();
+        let mut file = File::create(&partial).unwrap();
         file.write_all(&output.stdout).unwrap();
+        file.sync_all().unwrap();
+        commit_file(&intermed_file);
     }
     pub fn output(&self) -> &Output {
         &self.track_config.output
@@ -142,6 +469,27 @@ impl TrackData {
     pub fn build(&self) -> &Option<Build> {
         &self.track_config.build
     }
+    pub fn credits(&self) -> Option<String> {
+        self.track_config
+            .info
+            .as_ref()
+            .and_then(|i| i.credits.clone())
+    }
+    // How long processed.flac should be, without building anything
+    // Assumes output_command fills all of output_buffer
+    pub fn estimate_runtime(&self) -> Duration {
+        let sox = self.sox();
+        let bytes_per_second =
+            (sox.bit_depth as f64 / 8.0) * sox.channels as f64 * sox.sample_rate as f64;
+        let raw_seconds = Output::buffer_bytes(&self.output().output_buffer) as f64
+            / bytes_per_second
+            / sox.tempo.unwrap_or(1.0);
+        self.track_config
+            .clip
+            .clone()
+            .unwrap_or_default()
+            .runtime(Duration::milliseconds((raw_seconds * 1000.0) as i64))
+    }
     pub fn clips(&mut self) -> Clips {
         self.track_config.clip.clone().unwrap_or(vec![])
     }
@@ -162,6 +510,9 @@ impl Cache for Sox {
     fn self_type() -> &'static str {
         "sox"
     }
+    fn tool() -> Option<&'static str> {
+        Some("sox")
+    }
 }
 
 impl From<TrackConfig> for Sox {
@@ -178,6 +529,35 @@ pub struct Output {
     pub debug: Option<bool>,
     pub output_command: String,
     pub output_buffer: String,
+    pub inputs: Option<Vec<String>>,
+}
+
+impl Output {
+    // Follows head(1)'s --bytes format
+    pub fn buffer_bytes(output_buffer: &str) -> u64 {
+        let split = output_buffer
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(output_buffer.len());
+        let (number, suffix) = output_buffer.split_at(split);
+        let number: u64 = number
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid output_buffer '{}'", output_buffer));
+        let power = |base: u64, exp: u32| base.pow(exp);
+        number
+            * match suffix {
+                "" => 1,
+                "b" => 512,
+                "kB" => 1000,
+                "K" | "KiB" => 1024,
+                "MB" => power(1000, 2),
+                "M" | "MiB" => power(1024, 2),
+                "GB" => power(1000, 3),
+                "G" | "GiB" => power(1024, 3),
+                "TB" => power(1000, 4),
+                "T" | "TiB" => power(1024, 4),
+                other => panic!("invalid output_buffer suffix '{}'", other),
+            }
+    }
 }
 
 impl Cache for Output {
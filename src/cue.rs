@@ -0,0 +1,171 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::timeline::Timeline;
+
+// FLAC metadata block types we care about
+// https://xiph.org/flac/format.html#metadata_block_header
+const BLOCK_PADDING: u8 = 1;
+const BLOCK_CUESHEET: u8 = 5;
+
+// Builds a non-CD CUESHEET block so offsets can be sample-exact
+fn cuesheet_block(timeline: &Timeline) -> Vec<u8> {
+    // track numbers are a byte, and 255 is the lead-out
+    assert!(
+        timeline.entries.len() <= 254,
+        "A cue sheet can only hold 254 tracks, but the album has {}",
+        timeline.entries.len()
+    );
+    let mut block = vec![];
+    block.extend_from_slice(&[0; 128]); // media catalog number
+    block.extend_from_slice(&0u64.to_be_bytes()); // lead-in
+    block.extend_from_slice(&[0; 259]); // is_cd = 0, reserved
+    block.push(timeline.entries.len() as u8 + 1); // + lead-out
+    for (i, entry) in timeline.entries.iter().enumerate() {
+        block.extend_from_slice(&entry.start.to_be_bytes());
+        block.push(i as u8 + 1);
+        block.extend_from_slice(&[0; 12]); // ISRC
+        block.extend_from_slice(&[0; 14]); // audio, no pre-emphasis, reserved
+        block.push(1); // one index point
+        block.extend_from_slice(&0u64.to_be_bytes()); // relative to track offset
+        block.push(1);
+        block.extend_from_slice(&[0; 3]);
+    }
+    // lead-out
+    block.extend_from_slice(&timeline.total_samples.to_be_bytes());
+    block.push(255);
+    block.extend_from_slice(&[0; 12]);
+    block.extend_from_slice(&[0; 14]);
+    block.push(0);
+    block
+}
+
+// Replaces any CUESHEET block in a .flac file with one built from the timeline
+// The file is rewritten next to itself and moved into place
+pub fn embed_cuesheet(flac_file: &Path, timeline: &Timeline) {
+    println!("---> Embedding cuesheet into {}", flac_file.display());
+    let mut reader = BufReader::new(File::open(flac_file).expect("could not open flac file"));
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).unwrap();
+    assert!(
+        &magic == b"fLaC",
+        "{} is not a flac file",
+        flac_file.display()
+    );
+
+    let mut blocks: Vec<(u8, Vec<u8>)> = vec![];
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).unwrap();
+        let last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data).unwrap();
+        if block_type != BLOCK_CUESHEET {
+            blocks.push((block_type, data));
+        }
+        if last {
+            break;
+        }
+    }
+
+    // keep padding at the end so taggers can still use it
+    let position = blocks
+        .iter()
+        .position(|(t, _)| *t == BLOCK_PADDING)
+        .unwrap_or(blocks.len());
+    blocks.insert(position, (BLOCK_CUESHEET, cuesheet_block(timeline)));
+
+    let tmp_file = flac_file.with_extension("flac.tmp");
+    crate::command::add_partial(&tmp_file);
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_file).unwrap());
+        writer.write_all(b"fLaC").unwrap();
+        let n_blocks = blocks.len();
+        for (i, (block_type, data)) in blocks.into_iter().enumerate() {
+            let last_flag = if i + 1 == n_blocks { 0x80 } else { 0 };
+            let len = (data.len() as u32).to_be_bytes();
+            writer
+                .write_all(&[block_type | last_flag, len[1], len[2], len[3]])
+                .unwrap();
+            writer.write_all(&data).unwrap();
+        }
+        io::copy(&mut reader, &mut writer).unwrap();
+        writer.flush().unwrap();
+    }
+    fs::rename(&tmp_file, flac_file).unwrap();
+    crate::command::remove_partial(&tmp_file);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::TimelineEntry;
+
+    fn timeline(starts: &[u64], total_samples: u64) -> Timeline {
+        Timeline {
+            sample_rate: 44100,
+            total_samples,
+            entries: starts
+                .iter()
+                .enumerate()
+                .map(|(i, &start)| TimelineEntry {
+                    title: format!("track {}", i + 1),
+                    start,
+                    samples: 0,
+                })
+                .collect(),
+        }
+    }
+
+    // One track as laid out in the spec: offset, number, ISRC, flags and reserved,
+    // then a single index point at the start of the track
+    fn track(offset: u64, number: u8) -> Vec<u8> {
+        let mut track = offset.to_be_bytes().to_vec();
+        track.push(number);
+        track.extend_from_slice(&[0; 12 + 14]);
+        track.push(1);
+        track.extend_from_slice(&0u64.to_be_bytes());
+        track.extend_from_slice(&[1, 0, 0, 0]);
+        track
+    }
+
+    #[test]
+    fn cuesheet_block_layout() {
+        let mut expected = vec![0; 128]; // catalog number
+        expected.extend_from_slice(&[0; 8]); // lead-in
+        expected.extend_from_slice(&[0; 259]); // not a CD
+        expected.push(3); // two tracks and the lead-out
+        expected.extend(track(0, 1));
+        expected.extend(track(441_000, 2));
+        expected.extend_from_slice(&1_323_000u64.to_be_bytes());
+        expected.push(255); // lead-out, as numbered outside of CD-DA
+        expected.extend_from_slice(&[0; 12 + 14]);
+        expected.push(0); // no index points
+
+        let block = cuesheet_block(&timeline(&[0, 441_000], 1_323_000));
+        assert_eq!(block.len(), 396 + 2 * 48 + 36);
+        assert_eq!(block, expected);
+    }
+
+    #[test]
+    fn cuesheet_block_holds_254_tracks() {
+        let starts: Vec<u64> = (0..254).map(|i| i * 44100).collect();
+        let block = cuesheet_block(&timeline(&starts, 254 * 44100));
+        assert_eq!(block[395], 255);
+        // the last track's number and offset are where they'd be without wrapping
+        let last = 396 + 253 * 48;
+        assert_eq!(&block[last..last + 8], &(253u64 * 44100).to_be_bytes());
+        assert_eq!(block[last + 8], 254);
+    }
+
+    #[test]
+    #[should_panic(expected = "254 tracks")]
+    fn cuesheet_block_rejects_255_tracks() {
+        let starts: Vec<u64> = (0..255).collect();
+        cuesheet_block(&timeline(&starts, 255));
+    }
+}
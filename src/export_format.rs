@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::command::run;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Flac,
+    HiresFlac,
+    Opus,
+    Mp3,
+    Vorbis,
+}
+
+// [export] in an album config
+// Every field is optional; formats not listed here use their defaults
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct Export {
+    pub formats: Option<Vec<String>>,
+    pub flac: Option<FormatSettings>,
+    pub flac_hires: Option<FormatSettings>,
+    pub opus: Option<FormatSettings>,
+    pub mp3: Option<FormatSettings>,
+    pub vorbis: Option<FormatSettings>,
+}
+
+// Not every format uses every field. Unused fields are ignored
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct FormatSettings {
+    pub bitrate: Option<String>,
+    pub quality: Option<String>,
+    pub compression_level: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+}
+
+impl ExportFormat {
+    pub fn from_arg(arg: &str) -> Self {
+        match arg.trim() {
+            "flac" => Self::Flac,
+            "flac-hires" | "hires-flac" => Self::HiresFlac,
+            "opus" => Self::Opus,
+            "mp3" => Self::Mp3,
+            "vorbis" | "ogg" => Self::Vorbis,
+            other => panic!(
+                "export format '{}' is invalid. \
+		 Valid options are: flac, flac-hires, opus, mp3, vorbis.",
+                other
+            ),
+        }
+    }
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            Self::Flac => "flac",
+            Self::HiresFlac => "flac-hires",
+            Self::Opus => "opus",
+            Self::Mp3 => "mp3",
+            Self::Vorbis => "vorbis",
+        }
+    }
+    pub fn cue_file_type(&self) -> &'static str {
+        match self {
+            Self::Mp3 => "MP3",
+            _ => "WAVE",
+        }
+    }
+    pub fn is_flac(&self) -> bool {
+        matches!(self, Self::Flac | Self::HiresFlac)
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Flac | Self::HiresFlac => "flac",
+            Self::Opus => "opus",
+            Self::Mp3 => "mp3",
+            Self::Vorbis => "ogg",
+        }
+    }
+    pub fn settings(&self, export: &Export) -> FormatSettings {
+        match self {
+            Self::Flac => export.flac.clone(),
+            Self::HiresFlac => export.flac_hires.clone(),
+            Self::Opus => export.opus.clone(),
+            Self::Mp3 => export.mp3.clone(),
+            Self::Vorbis => export.vorbis.clone(),
+        }
+        .unwrap_or_default()
+    }
+    fn codec_args(&self, settings: &FormatSettings) -> Vec<OsString> {
+        match self {
+            Self::Flac => vec![
+                "-c:a".into(),
+                "flac".into(),
+                "-compression_level".into(),
+                settings.compression_level.unwrap_or(8).to_string().into(),
+            ],
+            Self::HiresFlac => vec![
+                "-c:a".into(),
+                "flac".into(),
+                "-compression_level".into(),
+                settings.compression_level.unwrap_or(8).to_string().into(),
+                "-ar".into(),
+                settings.sample_rate.unwrap_or(96000).to_string().into(),
+                "-sample_fmt".into(),
+                match settings.bit_depth.unwrap_or(24) {
+                    16 => "s16",
+                    _ => "s32", // flac stores s32 as 24 bit
+                }
+                .into(),
+            ],
+            Self::Opus => vec![
+                "-c:a".into(),
+                "libopus".into(),
+                "-b:a".into(),
+                settings
+                    .bitrate
+                    .clone()
+                    .unwrap_or_else(|| "160k".to_owned())
+                    .into(),
+            ],
+            Self::Mp3 => match &settings.bitrate {
+                // constant bitrate if asked for, V0 otherwise
+                Some(bitrate) => vec![
+                    "-c:a".into(),
+                    "libmp3lame".into(),
+                    "-b:a".into(),
+                    bitrate.into(),
+                ],
+                None => vec![
+                    "-c:a".into(),
+                    "libmp3lame".into(),
+                    "-q:a".into(),
+                    settings
+                        .quality
+                        .clone()
+                        .unwrap_or_else(|| "0".to_owned())
+                        .into(),
+                ],
+            },
+            Self::Vorbis => vec![
+                "-c:a".into(),
+                "libvorbis".into(),
+                "-q:a".into(),
+                settings
+                    .quality
+                    .clone()
+                    .unwrap_or_else(|| "6".to_owned())
+                    .into(),
+            ],
+        }
+    }
+    // tags are (key, value) pairs, passed as ffmpeg -metadata
+    // chapters is an optional FFMETADATA file, see Timeline::ffmetadata
+    pub fn transcode(
+        &self,
+        settings: &FormatSettings,
+        input: &Path,
+        output: &Path,
+        tags: &[(&str, String)],
+        chapters: Option<&Path>,
+    ) {
+        let mut args: Vec<OsString> = vec!["-v".into(), "error".into(), "-i".into(), input.into()];
+        if let Some(chapters) = chapters {
+            args.append(&mut vec![
+                "-i".into(),
+                chapters.into(),
+                "-map".into(),
+                "0:a".into(),
+                "-map_chapters".into(),
+                "1".into(),
+            ]);
+        }
+        args.append(&mut vec!["-map_metadata".into(), "-1".into()]);
+        args.append(&mut self.codec_args(settings));
+        for (key, value) in tags {
+            args.push("-metadata".into());
+            args.push(format!("{}={}", key, value).into());
+        }
+        args.push("-y".into());
+        args.push(output.into());
+
+        println!(
+            "---> ffmpeg {}",
+            args.iter()
+                .map(|a| a.to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        );
+
+        assert!(
+            run(Command::new("ffmpeg")
+                .args(&args)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()))
+            .expect("ffmpeg failed. Aborting.")
+            .status
+            .success(),
+            "ffmpeg failed. Aborting."
+        );
+    }
+}
+
+impl std::fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.dir_name())
+    }
+}
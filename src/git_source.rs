@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::command::{self, run};
+use crate::sources;
+use crate::track_name::TrackName;
+
+// One item of `git_sources` in [build]
+// Either a url, which follows the default branch:
+//     "https://github.com/tensorflow/tensorflow"
+// Or a table pinning it to a commit, tag, or branch:
+//     { url = "https://github.com/tensorflow/tensorflow", rev = "v2.4.0", depth = 1, submodules = false }
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum GitSource {
+    Url(String),
+    Pinned(GitPin),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct GitPin {
+    pub url: String,
+    pub rev: Option<String>,
+    // shallow clones fetch straight from upstream, so they aren't shared through target/sources/
+    pub depth: Option<u32>,
+    pub submodules: Option<bool>,
+}
+
+impl std::fmt::Display for GitSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.pin().rev {
+            Some(rev) => write!(f, "{} at {}", self.url(), rev),
+            None => write!(f, "{}", self.url()),
+        }
+    }
+}
+
+// Runs a git command that only reads, for its trimmed stdout
+fn git_output(dir: &Path, args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_owned())
+}
+
+fn git(dir: &Path, args: &[&str], what: &str) {
+    assert!(
+        run(Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()))
+        .unwrap_or_else(|_| panic!("{} failed", what))
+        .status
+        .success(),
+        "{} failed",
+        what
+    );
+}
+
+impl GitSource {
+    pub fn pin(&self) -> GitPin {
+        match self {
+            Self::Url(url) => GitPin {
+                url: url.clone(),
+                rev: None,
+                depth: None,
+                submodules: None,
+            },
+            Self::Pinned(pin) => pin.clone(),
+        }
+    }
+    pub fn url(&self) -> String {
+        self.pin().url
+    }
+    // The same source, checked out at exactly `commit`, for `build --locked`
+    pub fn at(&self, commit: &str) -> Self {
+        Self::Pinned(GitPin {
+            rev: Some(commit.to_owned()),
+            ..self.pin()
+        })
+    }
+    // What's checked out right now
+    pub fn commit(&self, track_name: &TrackName) -> Option<String> {
+        git_output(&self.dir(track_name), &["rev-parse", "HEAD"])
+    }
+    pub fn dir(&self, track_name: &TrackName) -> PathBuf {
+        track_name
+            .build_dir()
+            .join(Path::new(&self.url()).file_stem().unwrap())
+    }
+    // Where the clone should be fetching from
+    // Shallow clones use upstream directly, everything else the mirror
+    fn origin(&self) -> String {
+        match self.pin().depth {
+            Some(_) => self.url(),
+            None => sources::mirror_dir(&self.url()).display().to_string(),
+        }
+    }
+    // Clones made by an older sotclk, or before `depth` was added or removed,
+    // fetch from the wrong place and have to be cloned again
+    fn misplaced(&self, git_dir: &Path) -> bool {
+        git_dir.exists()
+            && git_output(git_dir, &["remote", "get-url", "origin"]) != Some(self.origin())
+    }
+    // The commit `rev` names in the clone, if it's there
+    // Branches are looked up on origin first, so they follow upstream
+    fn resolve(&self, git_dir: &Path) -> Option<String> {
+        let rev = self.pin().rev.unwrap_or_else(|| "HEAD".to_owned());
+        if let Some(depth) = self.pin().depth {
+            // shallow clones only have what was last fetched, as deep as it was fetched
+            let fetched = git_output(git_dir, &["config", "sotclk.rev"]) == Some(rev)
+                && git_output(git_dir, &["config", "sotclk.depth"]) == Some(depth.to_string());
+            return match fetched {
+                true => git_output(git_dir, &["rev-parse", "HEAD"]),
+                false => None,
+            };
+        }
+        [
+            format!("origin/{}^{{commit}}", rev),
+            format!("{}^{{commit}}", rev),
+        ]
+        .iter()
+        .find_map(|r| git_output(git_dir, &["rev-parse", "--verify", "--quiet", r]))
+    }
+    // Asks upstream where `rev` (or HEAD) is without fetching anything
+    // None if that can't be found out, like when offline or when rev is a commit
+    fn behind(&self, git_dir: &Path) -> Option<bool> {
+        let local = git_output(git_dir, &["rev-parse", "HEAD"])?;
+        let rev = self.pin().rev.unwrap_or_else(|| "HEAD".to_owned());
+        // annotated tags point at a tag object; the ^{} line has their commit
+        let peeled = format!("{}^{{}}", rev);
+        let upstream = git_output(git_dir, &["ls-remote", &self.url(), &rev, &peeled])?;
+        let commits: Vec<&str> = upstream
+            .lines()
+            .filter_map(|l| l.split_whitespace().next())
+            .collect();
+        match commits.is_empty() {
+            true => None,
+            false => Some(!commits.contains(&local.as_str())),
+        }
+    }
+    // Why this source needs fetching or checking out, if it does. Only reads
+    pub fn changes(&self, track_name: &TrackName, update: bool) -> Option<String> {
+        let git_dir = self.dir(track_name);
+        if !git_dir.exists() {
+            return Some(format!("git source {} not cloned", self));
+        }
+        if self.misplaced(&git_dir) {
+            return Some(format!("git source {} has to be cloned again", self));
+        }
+        if self.detached(&git_dir) {
+            return Some(format!("git source {} is still at a locked commit", self));
+        }
+        if (self.pin().rev.is_some() || self.pin().depth.is_some())
+            && self.resolve(&git_dir) != git_output(&git_dir, &["rev-parse", "HEAD"])
+        {
+            return Some(format!("git source {} isn't checked out", self));
+        }
+        if update && self.behind(&git_dir) == Some(true) {
+            return Some(format!("git source {} behind", self));
+        }
+        None
+    }
+    // Clones, updates, and checks out this source in the build directory
+    // True if the checkout changed
+    pub fn fetch(&self, track_name: &TrackName, update: bool) -> bool {
+        let git_dir = self.dir(track_name);
+        let before = git_output(&git_dir, &["rev-parse", "HEAD"]);
+        if self.misplaced(&git_dir) {
+            println!(
+                "---> {} doesn't fetch from {}; cloning again",
+                git_dir.display(),
+                self.origin()
+            );
+            if !command::dry_run() {
+                std::fs::remove_dir_all(&git_dir).unwrap();
+            }
+        }
+        let pin = self.pin();
+        match (&pin.rev, pin.depth) {
+            (None, None) => self.follow(track_name, &git_dir, update),
+            (_, Some(depth)) => self.fetch_shallow(&git_dir, depth, update),
+            (Some(rev), None) => self.fetch_rev(track_name, &git_dir, rev, update),
+        }
+        let after = git_output(&git_dir, &["rev-parse", "HEAD"]);
+        // every time, so turning submodules on or an interrupted update gets them checked out
+        if pin.submodules == Some(true) {
+            let mut args = vec!["submodule", "update", "--init", "--recursive"];
+            let depth = pin.depth.map(|d| d.to_string());
+            if let Some(depth) = &depth {
+                args.extend(&["--depth", depth]);
+            }
+            git(&git_dir, &args, "Git submodule update");
+        }
+        before != after || (command::dry_run() && before.is_none())
+    }
+    // A clone of the shared mirror in target/sources/
+    fn clone_from_mirror(&self, track_name: &TrackName, git_dir: &Path, update: bool) {
+        let mirror = sources::git_mirror(&self.url(), update);
+        git(
+            &track_name.build_dir(),
+            &[
+                "clone",
+                "--shared",
+                &mirror.to_string_lossy(),
+                &git_dir.to_string_lossy(),
+            ],
+            "Git clone",
+        );
+    }
+    // The mirror fetches from upstream, then the clone fetches from the mirror
+    fn update_from_mirror(&self, git_dir: &Path) {
+        sources::git_mirror(&self.url(), true);
+        git(git_dir, &["remote", "update"], "Git remote");
+    }
+    // Unpinned full clones are normally on a branch. They're only detached after `build --locked`
+    fn detached(&self, git_dir: &Path) -> bool {
+        let pin = self.pin();
+        pin.rev.is_none()
+            && pin.depth.is_none()
+            && git_dir.exists()
+            && git_output(git_dir, &["symbolic-ref", "-q", "HEAD"]).is_none()
+    }
+    // Unpinned sources follow whatever branch was cloned
+    fn follow(&self, track_name: &TrackName, git_dir: &Path, update: bool) {
+        if !git_dir.exists() {
+            self.clone_from_mirror(track_name, git_dir, update);
+            return;
+        }
+        if self.detached(git_dir) {
+            // back to the branch it was cloned on
+            let branch = git_output(git_dir, &["rev-parse", "--abbrev-ref", "origin/HEAD"])
+                .expect("can't find the default branch")
+                .trim_start_matches("origin/")
+                .to_owned();
+            git(git_dir, &["checkout", "--force", &branch], "Git checkout");
+        }
+        if !update {
+            return;
+        }
+        self.update_from_mirror(git_dir);
+        let git_status = Command::new("git")
+            .arg("status")
+            .arg("-uno")
+            .current_dir(git_dir)
+            .output()
+            .expect("Git status failed");
+        assert!(git_status.status.success(), "Git status failed");
+        if String::from_utf8_lossy(&git_status.stdout)
+            .lines()
+            .nth(1)
+            .unwrap_or("")
+            .starts_with("Your branch is behind")
+        {
+            git(git_dir, &["reset", "--hard"], "Git reset");
+            git(git_dir, &["pull"], "Git pull");
+        }
+    }
+    // Full clones share the mirror, and check out `rev` detached
+    // Only fetches when updating, or when `rev` isn't in the clone yet
+    fn fetch_rev(&self, track_name: &TrackName, git_dir: &Path, rev: &str, update: bool) {
+        if !git_dir.exists() {
+            self.clone_from_mirror(track_name, git_dir, update);
+        } else if update {
+            self.update_from_mirror(git_dir);
+        }
+        let commit = match self.resolve(git_dir) {
+            Some(commit) => commit,
+            None => {
+                if git_dir.exists() && !update {
+                    self.update_from_mirror(git_dir);
+                }
+                match self.resolve(git_dir) {
+                    Some(commit) => commit,
+                    None if command::dry_run() => return,
+                    None => panic!("Git source {}: can't find '{}'", self.url(), rev),
+                }
+            }
+        };
+        if git_output(git_dir, &["rev-parse", "HEAD"]).as_ref() != Some(&commit) {
+            git(
+                git_dir,
+                &["checkout", "--force", "--detach", &commit],
+                "Git checkout",
+            );
+        }
+    }
+    // Shallow clones fetch just `rev` (or HEAD) from upstream, `depth` commits deep
+    // The rev and depth last fetched are kept in the clone's config as sotclk.rev and sotclk.depth
+    fn fetch_shallow(&self, git_dir: &Path, depth: u32, update: bool) {
+        let rev = self.pin().rev.unwrap_or_else(|| "HEAD".to_owned());
+        if !git_dir.exists() {
+            git(
+                git_dir.parent().unwrap(),
+                &["init", "--quiet", &git_dir.to_string_lossy()],
+                "Git init",
+            );
+            git(
+                git_dir,
+                &["remote", "add", "origin", &self.url()],
+                "Git remote",
+            );
+        } else if !update && self.resolve(git_dir).is_some() {
+            return;
+        }
+        git(
+            git_dir,
+            &["fetch", "--depth", &depth.to_string(), "origin", &rev],
+            "Git fetch",
+        );
+        git(
+            git_dir,
+            &["checkout", "--force", "--detach", "FETCH_HEAD"],
+            "Git checkout",
+        );
+        git(git_dir, &["config", "sotclk.rev", &rev], "Git config");
+        git(
+            git_dir,
+            &["config", "sotclk.depth", &depth.to_string()],
+            "Git config",
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// One item of `http_sources` in [build]
+// Either a url, saved under the last part of its path:
+//     "https://cdn.kernel.org/pub/linux/kernel/v5.x/linux-5.10.tar.xz"
+// Or a table with the sha256 the download must have, and the name to save it as:
+//     { url = "https://codeload.github.com/torvalds/linux/tar.gz/v5.10", filename = "linux-5.10.tar.gz", sha256 = "..." }
+// Archives can be unpacked into the build directory instead of copied there:
+//     { url = "https://download.blender.org/demo/test/classroom.zip", extract = true, strip_components = 0 }
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum HttpSource {
+    Url(String),
+    Pinned(HttpPin),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HttpPin {
+    pub url: String,
+    pub sha256: Option<String>,
+    pub filename: Option<String>,
+    pub extract: Option<bool>,
+    pub strip_components: Option<usize>,
+}
+
+impl std::fmt::Display for HttpSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url())
+    }
+}
+
+impl HttpSource {
+    pub fn pin(&self) -> HttpPin {
+        match self {
+            Self::Url(url) => HttpPin {
+                url: url.clone(),
+                sha256: None,
+                filename: None,
+                extract: None,
+                strip_components: None,
+            },
+            Self::Pinned(pin) => pin.clone(),
+        }
+    }
+    pub fn url(&self) -> String {
+        self.pin().url
+    }
+    // Always lowercase, which is what hash_file gives
+    pub fn sha256(&self) -> Option<String> {
+        self.pin().sha256.map(|h| h.to_lowercase())
+    }
+    pub fn extract(&self) -> bool {
+        self.pin().extract == Some(true)
+    }
+    // What was extracted, as kept in http.manifest.toml: the archive's hash, and how it was unpacked
+    pub fn extraction(&self, hash: &str) -> String {
+        format!(
+            "{} strip_components={}",
+            hash,
+            self.pin().strip_components.unwrap_or(0)
+        )
+    }
+    // What it's called in the build directory
+    pub fn filename(&self) -> String {
+        let filename = self.pin().filename.unwrap_or_else(|| {
+            // query strings aren't part of the name
+            let path = self.url().split(['?', '#']).next().unwrap().to_owned();
+            Path::new(&path)
+                .file_name()
+                .unwrap_or_else(|| {
+                    panic!(
+                        "http source {} doesn't end in a file name; give it a `filename`",
+                        self
+                    )
+                })
+                .to_string_lossy()
+                .to_string()
+        });
+        assert!(
+            !filename.is_empty() && !filename.contains('/'),
+            "http source {}: filename '{}' has to be a plain file name",
+            self,
+            filename
+        );
+        filename
+    }
+    // Where it's kept in the track's target directory, and its key in http.manifest.toml
+    pub fn key(&self) -> String {
+        Path::new("http")
+            .join(self.filename())
+            .display()
+            .to_string()
+    }
+    // Key in http.manifest.toml for what was last extracted
+    pub fn extracted_key(&self) -> String {
+        format!("extracted/{}", self.filename())
+    }
+}
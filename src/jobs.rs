@@ -0,0 +1,144 @@
+use std::process::Command;
+use std::sync::mpsc;
+
+use crate::album_data::AlbumData;
+use crate::command::{self, run_prefixed};
+use crate::config::TrackConfig;
+use crate::lockfile;
+use crate::toplevel_album::get_albums;
+use crate::toplevel_track::{build_order, get_tracks};
+
+// One `sotclk build` for build-all -j, run as its own process so its output can be prefixed
+struct Job {
+    label: String,
+    args: Vec<String>, // after `build`
+    weight: usize,     // job slots it takes up
+    after: Vec<usize>, // jobs that have to finish first
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Waiting,
+    Running,
+    Done,
+    Failed,
+    Skipped,
+}
+
+fn jobs(matches: &clap::ArgMatches, slots: usize) -> Vec<Job> {
+    let tracks = build_order(get_tracks(matches));
+    let index = |name: &String| tracks.iter().position(|t| t.get_name() == *name).unwrap();
+    let mut jobs: Vec<Job> = tracks
+        .iter()
+        .map(|track_name| {
+            let config = TrackConfig::load(track_name);
+            Job {
+                label: track_name.get_name(),
+                args: vec!["track".to_owned(), track_name.get_name()],
+                weight: config.weight(slots),
+                after: config.dependencies().iter().map(index).collect(),
+            }
+        })
+        .collect();
+    for album_name in get_albums(matches) {
+        let members = AlbumData::load_from_track(&album_name).tracks();
+        jobs.push(Job {
+            label: format!("album {}", album_name),
+            args: vec!["album".to_owned(), album_name.get_name()],
+            weight: 1,
+            after: tracks
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| members.contains(&t.get_name()))
+                .map(|(i, _)| i)
+                .collect(),
+        });
+    }
+    jobs
+}
+
+// The same command line, minus build-all
+fn child_command(matches: &clap::ArgMatches, job: &Job) -> Command {
+    let mut cmd = Command::new(std::env::current_exe().expect("can't find sotclk itself"));
+    if let Some(track_dir) = matches.value_of("track_dir") {
+        cmd.arg("--track-dir").arg(track_dir);
+    }
+    if let Some(album_dir) = matches.value_of("album_dir") {
+        cmd.arg("--album-dir").arg(album_dir);
+    }
+    cmd.arg("build").args(&job.args).arg("--scheduled");
+    if command::dry_run() {
+        cmd.arg("--dry-run");
+    }
+    if lockfile::locked() {
+        cmd.arg("--locked");
+    }
+    cmd
+}
+
+// Builds everything with up to `slots` jobs at once
+// Tracks start in order as slots free up, albums as soon as their tracks are done
+pub fn build_all(matches: &clap::ArgMatches, slots: usize) {
+    assert!(slots > 0, "-j needs at least one job");
+    command::set_stage("build-all".to_owned());
+    let jobs = jobs(matches, slots);
+    let width = jobs.iter().map(|j| j.label.len()).max().unwrap_or(0);
+    let mut states = vec![State::Waiting; jobs.len()];
+    let mut free = slots;
+    let (tx, rx) = mpsc::channel();
+
+    loop {
+        for (i, job) in jobs.iter().enumerate() {
+            if states[i] != State::Waiting {
+                continue;
+            }
+            if job
+                .after
+                .iter()
+                .any(|&a| states[a] == State::Failed || states[a] == State::Skipped)
+            {
+                println!(
+                    "[{:<w$}] skipped, a track it needs failed",
+                    job.label,
+                    w = width
+                );
+                states[i] = State::Skipped;
+                continue;
+            }
+            if job.weight > free || job.after.iter().any(|&a| states[a] != State::Done) {
+                continue;
+            }
+            free -= job.weight;
+            states[i] = State::Running;
+            let mut cmd = child_command(matches, job);
+            let prefix = format!("[{:<w$}]", job.label, w = width);
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let ok = run_prefixed(&mut cmd, &prefix)
+                    .map(|status| status.success())
+                    .unwrap_or(false);
+                tx.send((i, ok)).unwrap();
+            });
+        }
+        if !states.contains(&State::Running) {
+            break;
+        }
+        let (i, ok) = rx.recv().unwrap();
+        free += jobs[i].weight;
+        states[i] = match ok {
+            true => State::Done,
+            false => {
+                println!("[{:<w$}] failed", jobs[i].label, w = width);
+                State::Failed
+            }
+        };
+    }
+
+    let failed: Vec<&str> = jobs
+        .iter()
+        .zip(&states)
+        .filter(|(_, s)| **s != State::Done)
+        .map(|(j, _)| j.label.as_str())
+        .collect();
+    assert!(failed.is_empty(), "Not built: {}", failed.join(", "));
+}
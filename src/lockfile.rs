@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::cache::Cache;
+use crate::clip::ClipsOpt;
+use crate::config::{Sox, Stage, TrackConfig, TrackData};
+use crate::manifest::Manifest;
+use crate::toplevel_track::get_tracks;
+use crate::track_name::TrackName;
+
+// sotclk.lock, written by `sotclk lock`
+// Records where each track's sources were and what it sounded like, so
+// `build --locked` can fetch the same sources and say whether the result still matches
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Lockfile {
+    pub tracks: BTreeMap<String, TrackLock>,
+}
+
+// git and http become tables in the file, so they have to come last
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct TrackLock {
+    // sha256 of intermediate.raw and processed.flac
+    pub raw: Option<String>,
+    pub processed: Option<String>,
+    // versions of the tools that made processed.flac
+    pub sox: Option<String>,
+    pub ffmpeg: Option<String>,
+    // url -> commit
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub git: BTreeMap<String, String>,
+    // url -> sha256 of the download
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub http: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    pub fn file() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("sotclk.lock")
+    }
+    pub fn load() -> Option<Self> {
+        fs::read_to_string(Self::file())
+            .ok()
+            .map(|s| toml::from_str(&s).expect("sotclk.lock is malformed"))
+    }
+    fn save(&self) {
+        fs::write(
+            Self::file(),
+            "# Written by `sotclk lock`. `sotclk build --locked` builds from these sources\n"
+                .to_owned()
+                + &toml::to_string(self).unwrap(),
+        )
+        .unwrap();
+    }
+}
+
+impl TrackLock {
+    // What's built right now. Only reads
+    fn current(track_name: &TrackName) -> Self {
+        let config = TrackConfig::load(track_name);
+        let mut lock = Self {
+            raw: Stage::Raw.output_hash(track_name),
+            processed: Stage::Clip.output_hash(track_name),
+            sox: Sox::recorded_tool(track_name),
+            ffmpeg: ClipsOpt::recorded_tool(track_name),
+            ..Self::default()
+        };
+        if let Some(build) = &config.build {
+            for source in &build.git_sources {
+                if let Some(commit) = source.commit(track_name) {
+                    lock.git.insert(source.url(), commit);
+                }
+            }
+            let manifest = Manifest::load(&track_name.dest_dir(), "http").unwrap_or_default();
+            for source in &build.http_sources {
+                if let Some(hash) = manifest.output(&source.key()) {
+                    lock.http.insert(source.url(), hash.clone());
+                }
+            }
+        }
+        lock
+    }
+    // After a locked build: why the track doesn't sound like it did when it was locked
+    pub fn differences(&self, track_name: &TrackName) -> Vec<String> {
+        let now = Self::current(track_name);
+        if now.processed == self.processed {
+            return vec![];
+        }
+        let mut reasons = vec![];
+        for (tool, old, new) in [
+            ("sox", &self.sox, &now.sox),
+            ("ffmpeg", &self.ffmpeg, &now.ffmpeg),
+        ] {
+            if old != new {
+                reasons.push(format!(
+                    "{} changed from '{}' to '{}'",
+                    tool,
+                    old.clone().unwrap_or_default(),
+                    new.clone().unwrap_or_default()
+                ));
+            }
+        }
+        if now.raw != self.raw {
+            reasons.push("output_command captured different data".to_owned());
+        } else if reasons.is_empty() {
+            reasons.push("the raw data matches, so [sox] or [[clip]] settings changed".to_owned());
+        }
+        reasons
+    }
+}
+
+// Set by `build --locked`
+static LOCKED: OnceLock<Lockfile> = OnceLock::new();
+
+pub fn use_lockfile() {
+    let lockfile = Lockfile::load().expect("sotclk.lock not found. Run `sotclk lock` first");
+    LOCKED.set(lockfile).ok();
+}
+
+pub fn locked() -> bool {
+    LOCKED.get().is_some()
+}
+
+// What sotclk.lock says about a track, when building with --locked
+pub fn track_lock(track_name: &TrackName) -> Option<&'static TrackLock> {
+    let lockfile = LOCKED.get()?;
+    Some(
+        lockfile
+            .tracks
+            .get(&track_name.get_name())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Track '{}' isn't in sotclk.lock. Build it and run `sotclk lock`",
+                    track_name
+                )
+            }),
+    )
+}
+
+// `sotclk lock`: records every built track, keeping old entries for tracks that aren't built
+pub fn lock_arg(matches: &clap::ArgMatches) {
+    let old = Lockfile::load().unwrap_or_default();
+    let mut lockfile = Lockfile::default();
+    for track_name in get_tracks(matches) {
+        let name = track_name.get_name();
+        let lock = TrackLock::current(&track_name);
+        if lock.processed.is_none() {
+            println!("--> Track '{}' isn't built, so it isn't locked", name);
+            if let Some(old_lock) = old.tracks.get(&name) {
+                lockfile.tracks.insert(name, old_lock.clone());
+            }
+            continue;
+        }
+        if !TrackData::load_from_track(&track_name)
+            .updates
+            .reasons
+            .is_empty()
+        {
+            println!(
+                "--> Track '{}' is out of date; locking what was last built",
+                name
+            );
+        }
+        if old.tracks.get(&name) != Some(&lock) {
+            println!("--> Locking track '{}'", name);
+        }
+        lockfile.tracks.insert(name, lock);
+    }
+    lockfile.save();
+    println!("Wrote {}", Lockfile::file().display());
+}
@@ -1,19 +1,33 @@
 mod album_data;
+mod album_entry;
 mod album_name;
+mod archive;
 mod build;
 mod cache;
 mod clip;
+mod command;
 mod config;
+mod cue;
+mod export_format;
+mod git_source;
+mod http_source;
+mod jobs;
+mod lockfile;
+mod manifest;
+mod sources;
 mod sox_args;
+mod timeline;
 mod toplevel_album;
 mod toplevel_track;
 mod track_name;
+mod tracklist;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 use std::fs;
 use std::path::PathBuf;
 
 fn main() {
+    command::handle_signals();
     let track_arg = Arg::with_name("track")
         .index(1)
         .required(true)
@@ -22,6 +36,14 @@ fn main() {
         .index(1)
         .required(true)
         .help("album config file name, found in albums/. Ex: 'ls'");
+    let dry_run_arg = Arg::with_name("dry_run")
+        .long("dry-run")
+        .global(true)
+        .help("Print every command that would run, with its arguments and working directory, without running anything or touching the cache");
+    let locked_arg = Arg::with_name("locked")
+        .long("locked")
+        .global(true)
+        .help("Check out the git commits and use the downloads recorded in sotclk.lock, then report tracks that no longer match it");
     let track_subcommand = SubCommand::with_name("track").arg(track_arg.clone());
     let album_subcommand = SubCommand::with_name("album").arg(album_arg.clone());
     let matches = App::new("Sounds of the Compiling Linux Kernel")
@@ -48,6 +70,21 @@ fn main() {
             SubCommand::with_name("build")
                 .about("Build an item, internally saving the result as a .flac file or set of .flac files")
 		.setting(AppSettings::SubcommandRequired)
+                .arg(dry_run_arg.clone())
+                .arg(locked_arg.clone())
+                .arg(
+                    Arg::with_name("explain")
+                        .long("explain")
+                        .global(true)
+                        .help("Before building each track, show which config fields, sources, and files changed since its last build")
+                )
+                .arg(
+                    Arg::with_name("scheduled")
+                        .long("scheduled")
+                        .global(true)
+                        .hidden(true)
+                        .help("Set by build-all -j, which has already built what this depends on")
+                )
                 .subcommand(track_subcommand.clone()
 			    .about("Build a track"))
                 .subcommand(album_subcommand.clone()
@@ -55,7 +92,18 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("build-all")
-                .about("Builds all tracks and albums, internally saving results as .flac"),
+                .about("Builds all tracks and albums, internally saving results as .flac")
+                .arg(dry_run_arg.clone())
+                .arg(locked_arg.clone())
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Build up to N tracks at once, each in its own process with its output prefixed by its name. \
+                               Albums start once their tracks are done")
+                ),
         )
         .subcommand(
             SubCommand::with_name("play")
@@ -64,6 +112,29 @@ fn main() {
                 .subcommand(track_subcommand.clone().about("Play a track using mpv"))
                 .subcommand(album_subcommand.clone().about("Build an album using mpv. Plays the compiled file"))
         )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Show how long an album would be, without building anything")
+		.setting(AppSettings::SubcommandRequired)
+                .subcommand(album_subcommand.clone()
+			    .about("Show cached or estimated track lengths, running time, and which tracks are stale"))
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Show why a track would rebuild: changed config fields with old and new values, sources, and files. Builds nothing")
+		.setting(AppSettings::SubcommandRequired)
+                .subcommand(track_subcommand.clone().about("Explain a track"))
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show which stages of each track and album are up to date, stale, or missing, and why. Builds nothing")
+                .subcommand(track_subcommand.clone().about("Show the status of a track"))
+                .subcommand(album_subcommand.clone().about("Show the status of an album's entries and master-track"))
+        )
+        .subcommand(
+            SubCommand::with_name("lock")
+                .about("Record the git commits, downloads, and output hashes of every built track in sotclk.lock")
+        )
         .subcommand(
             SubCommand::with_name("clean")
                 .about("Wipe the cache of a track or album, triggering a rebuild")
@@ -78,6 +149,7 @@ fn main() {
         .subcommand(
             SubCommand::with_name("export")
                 .about("Save a track's .flac somewhere, or export an entire album's directory")
+                .arg(dry_run_arg.clone())
                 .subcommand(track_subcommand.clone().arg(
                     Arg::with_name("output_file")
                         .index(2)
@@ -90,12 +162,20 @@ fn main() {
                         .index(2)
                         .required(true)
                         .help("Directory to save to. A subdirectory with all the album content will be created")
+                ).arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .value_name("FORMATS")
+                        .help("Comma separated list of formats to export, each in its own subdirectory. \
+                               Valid formats: flac, flac-hires, opus, mp3, vorbis. Ex: flac,opus,mp3")
                 )
 			    .about("Export the entire album directory. Includes individual tracks, a compiled master-track, and tracklist with some additional info"))
         )
         .get_matches();
 
-    if let Some(_) = matches.subcommand_matches("clean-all") {
+    if matches.subcommand_matches("clean-all").is_some() {
         fs::remove_dir_all(
             PathBuf::from(env!("CARGO_MANIFEST_DIR"))
                 .join("target")
@@ -108,25 +188,87 @@ fn main() {
                 .join("albums"),
         )
             .ok();
+        fs::remove_dir_all(sources::store_dir()).ok();
     } else if let Some(matches) = matches.subcommand_matches("clean") {
         if let Some(matches) = matches.subcommand_matches("track") {
             toplevel_track::clean_arg(matches);
         } else if let Some(matches) = matches.subcommand_matches("album") {
             toplevel_album::clean_arg(matches);
         }
+    } else if let Some(matches) = matches.subcommand_matches("lock") {
+        // Records what's already built, so it creates nothing either
+        lockfile::lock_arg(matches);
+    } else if let Some(matches) = matches.subcommand_matches("explain") {
+        // Like status, creates nothing
+        if let Some(matches) = matches.subcommand_matches("track") {
+            toplevel_track::explain_arg(matches);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("plan") {
+        // Like status, creates nothing
+        if let Some(matches) = matches.subcommand_matches("album") {
+            toplevel_album::plan_arg(matches);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("status") {
+        // Runs before setup_directories, so nothing gets created
+        if let Some(matches) = matches.subcommand_matches("track") {
+            toplevel_track::status_arg(matches);
+        } else if let Some(matches) = matches.subcommand_matches("album") {
+            toplevel_album::status_arg(matches);
+        } else {
+            for track_name in toplevel_track::get_tracks(matches) {
+                toplevel_track::print_status(&track_name);
+            }
+            for album_name in toplevel_album::get_albums(matches) {
+                toplevel_album::print_status(&album_name, matches);
+            }
+        }
     } else {
-        toplevel_track::setup_directories(&matches);
+        // --dry-run, --locked, and --explain can come before or after track/album
+        let dry_run = ["build", "build-all", "export"]
+            .iter()
+            .filter_map(|c| matches.subcommand_matches(c))
+            .any(|m| {
+                m.is_present("dry_run") || m.subcommand().1.is_some_and(|m| m.is_present("dry_run"))
+            });
+        if dry_run {
+            command::set_dry_run();
+        } else {
+            toplevel_track::setup_directories(&matches);
+        }
+        let locked = ["build", "build-all"]
+            .iter()
+            .filter_map(|c| matches.subcommand_matches(c))
+            .any(|m| {
+                m.is_present("locked") || m.subcommand().1.is_some_and(|m| m.is_present("locked"))
+            });
+        if locked {
+            lockfile::use_lockfile();
+        }
         if let Some(matches) = matches.subcommand_matches("build") {
+            if matches.is_present("explain")
+                || matches.subcommand().1.is_some_and(|m| m.is_present("explain"))
+            {
+                toplevel_track::explain_builds();
+            }
+            if matches.is_present("scheduled")
+                || matches.subcommand().1.is_some_and(|m| m.is_present("scheduled"))
+            {
+                toplevel_track::set_scheduled();
+            }
             if let Some(matches) = matches.subcommand_matches("track") {
                 toplevel_track::build_arg(matches);
             } else if let Some(matches) = matches.subcommand_matches("album") {
                 toplevel_album::build_arg(matches);
             }
         } else if let Some(matches) = matches.subcommand_matches("build-all") {
-            println!("Building tracks...");
-            toplevel_track::process_tracks(matches);
-            println!("Building albums...");
-            toplevel_album::process_albums(matches);
+            if let Some(slots) = matches.value_of("jobs") {
+                jobs::build_all(matches, slots.parse().expect("-j takes a number of jobs"));
+            } else {
+                println!("Building tracks...");
+                toplevel_track::process_tracks(matches);
+                println!("Building albums...");
+                toplevel_album::process_albums(matches);
+            }
         } else if let Some(matches) = matches.subcommand_matches("play") {
             if let Some(matches) = matches.subcommand_matches("track") {
                 toplevel_track::build_arg(matches);
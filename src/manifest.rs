@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::command;
+
+// sha256 of a file's contents, None if it can't be read
+pub fn hash_file(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).ok()?;
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+// Size and modification time of a file, for when hashing all of it would take too long
+pub fn fingerprint(path: &Path) -> Option<String> {
+    let meta = fs::metadata(path).ok()?;
+    let modified = meta
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some(format!(
+        "{} bytes, modified {}.{:09}",
+        meta.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    ))
+}
+
+fn partial_name(path: &Path) -> PathBuf {
+    path.with_file_name(format!(
+        "partial.{}",
+        path.file_name().unwrap().to_string_lossy()
+    ))
+}
+
+// Stage outputs are written under this name, then moved into place with `commit_file`
+// That way an interrupted stage never leaves a half-written file under the real name
+pub fn partial_file(path: &Path) -> PathBuf {
+    let partial = partial_name(path);
+    if command::dry_run() {
+        return partial;
+    }
+    fs::remove_file(&partial).ok(); // left over from an interrupted run
+    command::add_partial(&partial);
+    partial
+}
+
+pub fn commit_file(path: &Path) {
+    if command::dry_run() {
+        return;
+    }
+    let partial = partial_name(path);
+    fs::rename(&partial, path)
+        .unwrap_or_else(|_| panic!("{} was never written", partial.display()));
+    command::remove_partial(&partial);
+}
+
+// Content hashes of what a stage read and what it wrote
+// Keys are paths relative to the directory the manifest lives in
+// Stored as <stage>.manifest.toml
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub inputs: BTreeMap<String, String>,
+    pub outputs: BTreeMap<String, String>,
+    // Size and modification time of the outputs when they were hashed,
+    // so checking them again only reads the ones that look different
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fingerprints: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    fn file(dir: &Path, stage: &str) -> PathBuf {
+        dir.join(format!("{}.manifest.toml", stage))
+    }
+    pub fn load(dir: &Path, stage: &str) -> Option<Self> {
+        fs::read_to_string(Self::file(dir, stage))
+            .ok()
+            .and_then(|s| toml::from_str(&s).ok())
+    }
+    pub fn save(&self, dir: &Path, stage: &str) {
+        if command::dry_run() {
+            return;
+        }
+        fs::write(Self::file(dir, stage), toml::to_string(self).unwrap()).unwrap();
+    }
+    pub fn remove(dir: &Path, stage: &str) {
+        if command::dry_run() {
+            return;
+        }
+        fs::remove_file(Self::file(dir, stage)).ok();
+    }
+    // Hashes the given files, which are relative to dir, as this manifest's outputs
+    pub fn hash_outputs(&mut self, dir: &Path, names: &[&str]) {
+        for name in names {
+            let path = dir.join(name);
+            if let (Some(hash), Some(print)) = (hash_file(&path), fingerprint(&path)) {
+                self.outputs.insert(name.to_string(), hash);
+                self.fingerprints.insert(name.to_string(), print);
+            }
+        }
+    }
+    pub fn output(&self, name: &str) -> Option<&String> {
+        self.outputs.get(name)
+    }
+    // The first output that's gone or was changed since the stage wrote it, as a reason
+    pub fn damaged_output(&self, dir: &Path) -> Option<String> {
+        self.outputs
+            .iter()
+            .filter(|(name, _)| {
+                let print = fingerprint(&dir.join(name));
+                print.is_none() || print.as_ref() != self.fingerprints.get(*name)
+            })
+            .find_map(|(name, hash)| match hash_file(&dir.join(name)) {
+                None => Some(format!("{} missing", name)),
+                Some(h) if &h != hash => Some(format!("{} was modified", name)),
+                _ => None,
+            })
+    }
+}
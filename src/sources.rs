@@ -0,0 +1,419 @@
+use curl::easy::{Easy, List};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::cache::lock;
+use crate::command::{self, run};
+use crate::manifest::{hash_file, Manifest};
+
+// target/sources/ holds every git repo and http download, shared between tracks
+// so the same repo or tarball is only fetched and stored once
+// `clean track` leaves it alone; `clean-all` wipes it
+pub fn store_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("sources")
+}
+
+// Where a url lives in the store: a short hash of the url, then a readable name
+fn url_key(url: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    let path = url.split(['?', '#']).next().unwrap();
+    format!(
+        "{}-{}",
+        &hash[..16],
+        Path::new(path).file_name().unwrap().to_string_lossy()
+    )
+}
+
+pub fn mirror_dir(url: &str) -> PathBuf {
+    store_dir()
+        .join("git")
+        .join(format!("{}.git", url_key(url)))
+}
+
+// A bare mirror of a git repo, cloned the first time any track asks for it
+// Tracks clone from the mirror with --shared, so objects are stored once
+// Branches deleted upstream are kept, since a clone may still have them checked out
+pub fn git_mirror(url: &str, update: bool) -> PathBuf {
+    let dir = store_dir().join("git");
+    let mirror = mirror_dir(url);
+    let _lock = lock(
+        &dir.join(format!("{}.lock", url_key(url))),
+        &format!("fetching {}", url),
+    );
+    if !mirror.exists() {
+        if !command::dry_run() {
+            fs::create_dir_all(&dir).unwrap();
+        }
+        assert!(
+            run(Command::new("git")
+                .arg("clone")
+                .arg("--mirror")
+                .arg(url)
+                .arg(&mirror)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()))
+            .expect("Git clone failed")
+            .status
+            .success(),
+            "Git clone failed"
+        );
+    } else if update {
+        assert!(
+            run(Command::new("git")
+                .arg("remote")
+                .arg("update")
+                .current_dir(&mirror)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit()))
+            .expect("Git remote failed")
+            .status
+            .success(),
+            "Git remote failed"
+        );
+    }
+    // Objects the mirror no longer needs can still be used by the clones sharing it,
+    // so it never garbage collects or prunes them
+    if mirror.exists() {
+        for (key, value) in &[("gc.auto", "0"), ("gc.pruneExpire", "never")] {
+            assert!(
+                run(Command::new("git")
+                    .arg("config")
+                    .arg(key)
+                    .arg(value)
+                    .current_dir(&mirror))
+                .expect("Git config failed")
+                .status
+                .success(),
+                "Git config failed"
+            );
+        }
+    }
+    mirror
+}
+
+// Headers a server gives for a url that say whether it changed, kept in the url's manifest
+const VALIDATORS: [&str; 3] = ["ETag", "Last-Modified", "Content-Length"];
+const ATTEMPTS: u32 = 5;
+
+// One response, or the last one after redirects
+#[derive(Default)]
+struct Response {
+    code: u32,
+    headers: BTreeMap<String, String>, // just VALIDATORS
+}
+
+impl Response {
+    fn header(&mut self, data: &[u8]) {
+        let line = String::from_utf8_lossy(data);
+        if line.starts_with("HTTP/") {
+            *self = Self::default(); // a redirect, or the real response after it
+            self.code = line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(0);
+        } else if let Some((name, value)) = line.split_once(':') {
+            if let Some(name) = VALIDATORS
+                .iter()
+                .find(|v| v.eq_ignore_ascii_case(name.trim()))
+            {
+                self.headers
+                    .insert(name.to_string(), value.trim().to_owned());
+            }
+        }
+    }
+    // file:// urls don't have status codes
+    fn ok(&self) -> bool {
+        self.code == 0 || (200..300).contains(&self.code)
+    }
+}
+
+fn easy(url: &str) -> Easy {
+    let mut easy = Easy::new();
+    easy.url(url).unwrap();
+    easy.follow_location(true).unwrap();
+    easy.connect_timeout(Duration::from_secs(30)).unwrap();
+    // a stalled transfer fails, and gets retried
+    easy.low_speed_limit(1).unwrap();
+    easy.low_speed_time(Duration::from_secs(60)).unwrap();
+    easy
+}
+
+// Headers to send so the server can answer 304 Not Modified
+fn conditional(recorded: &BTreeMap<String, String>) -> List {
+    let mut list = List::new();
+    if let Some(etag) = recorded.get("ETag") {
+        list.append(&format!("If-None-Match: {}", etag)).unwrap();
+    }
+    if let Some(date) = recorded.get("Last-Modified") {
+        list.append(&format!("If-Modified-Since: {}", date))
+            .unwrap();
+    }
+    list
+}
+
+// What the store last downloaded from a url: its hash as the output, its validators as inputs
+fn record(url: &str) -> Manifest {
+    Manifest::load(&store_dir().join("http"), &url_key(url)).unwrap_or_default()
+}
+
+// The hash of the last download of a url in the store
+pub fn recorded_hash(url: &str) -> Option<String> {
+    record(url).output(url).cloned()
+}
+
+// Asks upstream whether a url changed since the store downloaded it, without downloading it
+// None if that can't be found out, like when offline or in a dry run
+pub fn changed_upstream(url: &str) -> Option<bool> {
+    if command::dry_run() {
+        return None; // no network, so assume it's unchanged
+    }
+    let recorded = record(url).inputs;
+    let mut easy = easy(url);
+    easy.nobody(true).unwrap();
+    easy.http_headers(conditional(&recorded)).unwrap();
+    let mut response = Response::default();
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .header_function(|data| {
+                response.header(data);
+                true
+            })
+            .unwrap();
+        transfer.perform().ok()?;
+    }
+    if response.code == 304 {
+        return Some(false);
+    }
+    if !response.ok() {
+        return None;
+    }
+    // servers that ignore the conditional headers are compared by hand
+    Some(
+        response
+            .headers
+            .iter()
+            .any(|(name, value)| recorded.get(name) != Some(value)),
+    )
+}
+
+// Why one try at downloading failed, and whether trying again could help
+struct Failed {
+    why: String,
+    retry: bool,
+}
+
+// Downloads a url to `part`, carrying on from where an earlier try stopped
+// `part` isn't removed on an interrupt, so the next run resumes it
+// The validators it started with are kept next to it, so it's only resumed if upstream hasn't changed
+fn fetch_once(url: &str, dir: &Path, part: &Path, part_name: &str) -> Result<Response, Failed> {
+    let started = Manifest::load(dir, part_name).unwrap_or_default().inputs;
+    // Content-Length alone can't go in If-Range, so it can't tell whether it's the same file
+    let validator = started.get("ETag").or_else(|| started.get("Last-Modified"));
+    let offset = match validator {
+        Some(_) => fs::metadata(part).map(|m| m.len()).unwrap_or(0),
+        None => 0,
+    };
+    let mut easy = easy(url);
+    easy.progress(true).unwrap();
+    if let Some(validator) = validator.filter(|_| offset > 0) {
+        println!("---> resuming at {} bytes", offset);
+        easy.resume_from(offset).unwrap();
+        // If-Range makes the server send the whole file if it changed since
+        let mut list = List::new();
+        list.append(&format!("If-Range: {}", validator)).unwrap();
+        easy.http_headers(list).unwrap();
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(part)
+        .unwrap();
+    if offset == 0 {
+        file.set_len(0).unwrap();
+    }
+    // read while writing the body, so it can't just be borrowed by both
+    let response = RefCell::new(Response::default());
+    let mut writing = false;
+    let mut reported = 0;
+    let result = {
+        let mut transfer = easy.transfer();
+        transfer
+            .header_function(|data| {
+                response.borrow_mut().header(data);
+                true
+            })
+            .unwrap();
+        transfer
+            .write_function(|data| {
+                let response = response.borrow();
+                if !response.ok() {
+                    return Ok(data.len()); // an error page
+                }
+                if !writing && offset == 0 {
+                    Manifest {
+                        inputs: response.headers.clone(),
+                        ..Manifest::default()
+                    }
+                    .save(dir, part_name);
+                }
+                writing = true;
+                match file.write_all(data) {
+                    Ok(()) => Ok(data.len()),
+                    Err(_) => Ok(0), // makes curl fail
+                }
+            })
+            .unwrap();
+        transfer
+            .progress_function(|total, now, _, _| {
+                // every tenth of the way, counting what was there before resuming
+                let (total, now) = (total + offset as f64, now + offset as f64);
+                if total > offset as f64 && (now / total * 10.0) as u32 > reported {
+                    reported = (now / total * 10.0) as u32;
+                    println!("---> {}% of {:.1} MB", reported * 10, total / 1e6);
+                }
+                true
+            })
+            .unwrap();
+        transfer.perform()
+    };
+    let response = response.into_inner();
+    match (result, response.code) {
+        (Err(e), _) if e.is_range_error() => {
+            // If-Range didn't match, or the server can't resume
+            fs::remove_file(part).ok();
+            Err(Failed {
+                why: "can't resume, upstream changed or doesn't allow it".to_owned(),
+                retry: true,
+            })
+        }
+        (_, 416) => {
+            // the partial file is bigger than the file now is
+            fs::remove_file(part).ok();
+            Err(Failed {
+                why: "the partial download doesn't fit upstream".to_owned(),
+                retry: true,
+            })
+        }
+        (Err(e), _) => Err(Failed {
+            why: e.to_string(),
+            retry: true,
+        }),
+        (Ok(()), code) if !response.ok() => Err(Failed {
+            why: format!("server answered {}", code),
+            // client errors like 404 won't go away by asking again
+            retry: !(400..500).contains(&code) || code == 408 || code == 429,
+        }),
+        (Ok(()), _) => Ok(response),
+    }
+}
+
+// Tries a few times, waiting longer each time
+fn fetch(url: &str, dir: &Path, part: &Path, part_name: &str) -> Response {
+    let mut wait = 2;
+    for attempt in 1..=ATTEMPTS {
+        match fetch_once(url, dir, part, part_name) {
+            Ok(response) => return response,
+            Err(failed) if failed.retry && attempt < ATTEMPTS => {
+                println!(
+                    "---> download failed ({}); trying again in {}s",
+                    failed.why, wait
+                );
+                std::thread::sleep(Duration::from_secs(wait));
+                wait *= 2;
+            }
+            Err(failed) => {
+                // anything that did arrive is kept, to resume next time
+                if fs::metadata(part).map(|m| m.len()).unwrap_or(0) == 0 {
+                    fs::remove_file(part).ok();
+                    Manifest::remove(dir, part_name);
+                }
+                panic!("Downloading {} failed: {}", url, failed.why)
+            }
+        }
+    }
+    unreachable!()
+}
+
+// Downloads are stored by content hash under sources/http/, with
+// a manifest per url recording which hash it last gave and its ETag, Last-Modified, and Content-Length
+// A url is only downloaded again if upstream changed since, or `reuse` is false
+// With `sha256`, a stored copy with that hash is used without asking upstream,
+// and a download with any other hash fails
+// Returns the stored file, or None in a dry run
+pub fn download(url: &str, sha256: Option<&str>, reuse: bool) -> Option<PathBuf> {
+    let dir = store_dir().join("http");
+    if let Some(stored) = sha256.and_then(stored) {
+        println!("---> already in {}", stored.display());
+        return Some(stored);
+    }
+    let key = url_key(url);
+    let _lock = lock(
+        &dir.join(format!("{}.lock", key)),
+        &format!("downloading {}", url),
+    );
+    if let Some(hash) = recorded_hash(url).filter(|_| reuse && sha256.is_none()) {
+        if hash_file(&dir.join(&hash)).as_ref() == Some(&hash)
+            && changed_upstream(url) != Some(true)
+        {
+            println!("---> already in {}", dir.join(&hash).display());
+            return Some(dir.join(hash));
+        }
+    }
+    if command::dry_run() {
+        println!("---> would download {} to {}", url, dir.display());
+        return None;
+    }
+
+    fs::create_dir_all(&dir).unwrap();
+    let part_name = format!("{}.part", key);
+    let part = dir.join(&part_name);
+    let response = fetch(url, &dir, &part, &part_name);
+    let hash = hash_file(&part).expect("download disappeared");
+    if let Some(expected) = sha256 {
+        if hash != expected {
+            fs::remove_file(&part).ok();
+            Manifest::remove(&dir, &part_name);
+            panic!(
+                "Download of {} has sha256 {}, but {} was expected",
+                url, hash, expected
+            );
+        }
+    }
+    fs::rename(&part, dir.join(&hash)).unwrap();
+    Manifest::remove(&dir, &part_name);
+    let mut manifest = Manifest::default();
+    manifest.outputs.insert(url.to_owned(), hash.clone());
+    manifest.inputs = response.headers;
+    manifest.save(&dir, &key);
+    Some(dir.join(hash))
+}
+
+// A download that's already in the store, by its sha256
+pub fn stored(hash: &str) -> Option<PathBuf> {
+    let file = store_dir().join("http").join(hash);
+    match hash_file(&file).as_deref() == Some(hash) {
+        true => Some(file),
+        false => None,
+    }
+}
+
+// Puts a stored file at `dest`, as a hardlink where the filesystem allows it
+pub fn link(stored: &Path, dest: &Path) {
+    if command::dry_run() {
+        return;
+    }
+    fs::remove_file(dest).ok();
+    if fs::hard_link(stored, dest).is_err() {
+        fs::copy(stored, dest).unwrap();
+    }
+}
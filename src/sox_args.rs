@@ -1,11 +1,15 @@
 use std::ffi::OsString;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
+use crate::command::run;
 use crate::config::TrackData;
+use crate::manifest::{commit_file, partial_file};
 use crate::track_name::TrackName;
 
 pub struct SoxArgs {
     args: Vec<OsString>,
+    output: PathBuf,
     buffer_args_n_pre: usize,
     buffer_args_n_post: usize,
 }
@@ -76,33 +80,32 @@ impl SoxArgs {
             0
         };
 
-        sox_args.push(
-            track_name
-                .dest_dir()
-                .join(TrackData::unprocessed_filename())
-                .into_os_string(),
-        );
+        let output = track_name
+            .dest_dir()
+            .join(TrackData::unprocessed_filename());
+        sox_args.push(partial_file(&output).into_os_string());
 
         Self {
             args: sox_args,
+            output,
             buffer_args_n_pre: other_n_pre,
             buffer_args_n_post: other_n_post,
         }
     }
     pub fn execute(&self) {
-        std::fs::remove_file(self.args.last().unwrap()).ok(); // makes cache happy
-
         let mut sox_cmd = Command::new("sox");
         sox_cmd.args(&self.args);
 
         println!("---> sox {}", self);
 
-        let sox_output = sox_cmd.output().expect("Sox command failed");
+        let sox_output =
+            run(sox_cmd.stdout(Stdio::piped()).stderr(Stdio::piped())).expect("Sox command failed");
 
         if !sox_output.status.success() {
             eprintln!("{}", String::from_utf8_lossy(&sox_output.stderr));
             panic!("Sox command failed");
         }
+        commit_file(&self.output);
     }
 }
 
@@ -0,0 +1,175 @@
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Where every track sits inside the album master, in samples
+// Built from the exact files handed to ffmpeg's concat demuxer
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Timeline {
+    pub sample_rate: u64,
+    pub total_samples: u64,
+    pub entries: Vec<TimelineEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TimelineEntry {
+    pub title: String,
+    pub start: u64,
+    pub samples: u64,
+}
+
+impl Timeline {
+    pub fn filename() -> &'static str {
+        "timeline.toml"
+    }
+    // returns (samples, sample_rate)
+    pub fn probe(file: &Path) -> (u64, u64) {
+        let cmd = Command::new("ffprobe")
+            .arg("-v")
+            .arg("error")
+            .arg("-select_streams")
+            .arg("a:0")
+            .arg("-show_entries")
+            .arg("stream=sample_rate,duration_ts")
+            .arg("-of")
+            .arg("default=noprint_wrappers=1")
+            .arg(file)
+            .output()
+            .expect("ffprobe failed");
+
+        assert!(cmd.status.success(), "ffprobe failed");
+
+        let stdout = String::from_utf8_lossy(&cmd.stdout).to_string();
+        let field = |name: &str| -> u64 {
+            stdout
+                .lines()
+                .find(|l| l.starts_with(&format!("{}=", name)))
+                .and_then(|l| l.split('=').nth(1))
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or_else(|| panic!("ffprobe gave no {} for {}", name, file.display()))
+        };
+        (field("duration_ts"), field("sample_rate"))
+    }
+    // Each track is followed by one copy of `gap`, same as the concat list
+    pub fn new(tracks: &[(String, PathBuf)], gap: &Path) -> Self {
+        let (gap_samples, gap_rate) = Self::probe(gap);
+        let mut sample_rate = None;
+        let mut entries = vec![];
+        let mut position = 0;
+        for (title, path) in tracks {
+            let (samples, rate) = Self::probe(path);
+            // the master takes the format of the first file
+            let master_rate = *sample_rate.get_or_insert(rate);
+            let samples = samples * master_rate / rate;
+            entries.push(TimelineEntry {
+                title: title.clone(),
+                start: position,
+                samples,
+            });
+            position += samples + gap_samples * master_rate / gap_rate;
+        }
+        Self {
+            sample_rate: sample_rate.unwrap_or(gap_rate),
+            total_samples: position,
+            entries,
+        }
+    }
+    pub fn duration(&self, samples: u64) -> Duration {
+        Duration::nanoseconds((samples as u128 * 1_000_000_000 / self.sample_rate as u128) as i64)
+    }
+    // The master should be exactly as long as the files that went into it
+    pub fn verify(&self, master: &Path) {
+        let (samples, rate) = Self::probe(master);
+        let samples = samples * self.sample_rate / rate;
+        assert!(
+            samples == self.total_samples,
+            "Album master {} is {} samples long, but its tracks add up to {} samples",
+            master.display(),
+            samples,
+            self.total_samples
+        );
+        println!(
+            "---> Verified master length: {} samples at {}Hz",
+            samples, self.sample_rate
+        );
+    }
+    pub fn resampled(&self, sample_rate: u64) -> Self {
+        let scale = |s: u64| s * sample_rate / self.sample_rate;
+        Self {
+            sample_rate,
+            total_samples: scale(self.total_samples),
+            entries: self
+                .entries
+                .iter()
+                .map(|e| TimelineEntry {
+                    title: e.title.clone(),
+                    start: scale(e.start),
+                    samples: scale(e.samples),
+                })
+                .collect(),
+        }
+    }
+    pub fn save(&self, dir: &Path) {
+        let mut file = File::create(dir.join(Self::filename())).unwrap();
+        file.write_all(toml::to_string(self).unwrap().as_bytes())
+            .unwrap();
+    }
+    pub fn load(dir: &Path) -> Option<Self> {
+        fs::read_to_string(dir.join(Self::filename()))
+            .ok()
+            .and_then(|s| toml::from_str(&s).ok())
+    }
+    // CUE sheets count in CD frames, 75 per second
+    fn cue_time(&self, samples: u64) -> String {
+        let frames = samples * 75 / self.sample_rate;
+        format!(
+            "{:02}:{:02}:{:02}",
+            frames / 75 / 60,
+            frames / 75 % 60,
+            frames % 75
+        )
+    }
+    pub fn cue_sheet(&self, album_title: &str, file_name: &str, file_type: &str) -> String {
+        let quote = |s: &str| s.replace('"', "'");
+        let mut cue = format!(
+            "TITLE \"{}\"\nFILE \"{}\" {}\n",
+            quote(album_title),
+            quote(file_name),
+            file_type
+        );
+        for (i, entry) in self.entries.iter().enumerate() {
+            cue += &format!(
+                "  TRACK {:02} AUDIO\n    TITLE \"{}\"\n    INDEX 01 {}\n",
+                i + 1,
+                quote(&entry.title),
+                self.cue_time(entry.start)
+            );
+        }
+        cue
+    }
+    // ffmpeg's FFMETADATA format, used for chapter markers in lossy exports
+    pub fn ffmetadata(&self, album_title: &str) -> String {
+        let escape = |s: &str| {
+            s.chars()
+                .flat_map(|c| match c {
+                    '=' | ';' | '#' | '\\' | '\n' => vec!['\\', c],
+                    c => vec![c],
+                })
+                .collect::<String>()
+        };
+        let mut meta = format!(";FFMETADATA1\ntitle={}\n", escape(album_title));
+        for entry in &self.entries {
+            meta += &format!(
+                "\n[CHAPTER]\nTIMEBASE=1/{}\nSTART={}\nEND={}\ntitle={}\n",
+                self.sample_rate,
+                entry.start,
+                entry.start + entry.samples,
+                escape(&entry.title)
+            );
+        }
+        meta
+    }
+}
@@ -4,6 +4,11 @@ use std::process::{Command, Stdio};
 
 use crate::album_data::AlbumData;
 use crate::album_name::AlbumName;
+use crate::cache::CacheTarget;
+use crate::command;
+use crate::export_format::ExportFormat;
+use crate::timeline::Timeline;
+use crate::tracklist::retarget_playlist;
 
 pub fn process_albums(matches: &clap::ArgMatches) {
     for album_name in get_albums(matches) {
@@ -21,32 +26,171 @@ pub fn export_arg(matches: &clap::ArgMatches) {
     println!("Exporting...");
     let album_name = AlbumName::new_from_arg(matches);
     let album_data = AlbumData::load_from_track(&album_name);
+    let export = album_data.album_config.export.clone().unwrap_or_default();
 
     let old_dir = album_name.dest_dir();
     let new_dir = PathBuf::from(matches.value_of("output_dir").unwrap())
-        .join(album_data.album_config.album.title);
+        .join(&album_data.album_config.album.title);
 
-    Command::new("rm")
-        .arg("-rf")
-        .arg(&new_dir)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()
-        .expect("export command failed");
-
-    assert!(
-        Command::new("cp")
-            .arg("-r")
-            .arg(old_dir)
-            .arg(new_dir)
+    command::run(
+        Command::new("rm")
+            .arg("-rf")
+            .arg(&new_dir)
             .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()
-            .expect("export command failed")
-            .status
-            .success(),
-        "export command failed"
-    );
+            .stderr(Stdio::inherit()),
+    )
+    .expect("export command failed");
+
+    let formats: Vec<ExportFormat> = match (matches.values_of("format"), &export.formats) {
+        (Some(args), _) => args.map(ExportFormat::from_arg).collect(),
+        (None, Some(formats)) => formats.iter().map(|f| ExportFormat::from_arg(f)).collect(),
+        (None, None) => vec![],
+    };
+
+    let track_datas = album_data.titled_tracks(matches);
+    let album_title = album_data.album_config.album.title.clone();
+
+    if formats.is_empty() {
+        // plain copy of what the album is made of, leaving out the bookkeeping around it
+        let mut files: Vec<PathBuf> = track_datas
+            .iter()
+            .map(|(title, _)| {
+                PathBuf::from(AlbumData::track_dir_name()).join(format!("{}.flac", title))
+            })
+            .collect();
+        files.push(format!("{}.flac", album_title).into());
+        files.push(format!("{}.cue", album_title).into());
+        files.extend(album_data.tracklist_files().into_iter().map(PathBuf::from));
+        if !command::dry_run() {
+            fs::create_dir_all(new_dir.join(AlbumData::track_dir_name())).unwrap();
+        }
+        for file in files {
+            assert!(
+                command::run(
+                    Command::new("cp")
+                        .arg(old_dir.join(&file))
+                        .arg(new_dir.join(&file))
+                        .stdout(Stdio::inherit())
+                        .stderr(Stdio::inherit())
+                )
+                .expect("export command failed")
+                .status
+                .success(),
+                "export command failed"
+            );
+        }
+        return;
+    }
+
+    for format in formats {
+        println!("--> Exporting {}", format);
+        command::set_stage(format!("{} export of album '{}'", format, album_name));
+        let settings = format.settings(&export);
+        let format_dir = new_dir.join(format.dir_name());
+        if !command::dry_run() {
+            fs::create_dir_all(format_dir.join(AlbumData::track_dir_name())).unwrap();
+        }
+
+        for (i, (title, track_data)) in track_datas.iter().enumerate() {
+            let mut tags = album_data.album_tags();
+            tags.push(("title", title.clone()));
+            tags.push(("track", format!("{}/{}", i + 1, track_datas.len())));
+            if let Some(credits) = track_data.as_ref().and_then(|td| td.credits()) {
+                tags.push(("comment", credits));
+            }
+            format.transcode(
+                &settings,
+                &old_dir
+                    .join(AlbumData::track_dir_name())
+                    .join(format!("{}.flac", title)),
+                &format_dir.join(AlbumData::track_dir_name()).join(format!(
+                    "{}.{}",
+                    title,
+                    format.extension()
+                )),
+                &tags,
+                None,
+            );
+        }
+
+        let mut master_tags = album_data.album_tags();
+        master_tags.push(("title", album_title.clone()));
+        if let Some(description) = &album_data.album_config.description {
+            master_tags.push(("comment", description.clone()));
+        }
+        let master_file = format_dir.join(format!("{}.{}", album_title, format.extension()));
+        let chapters_file = format_dir.join("chapters.ffmetadata");
+        if command::dry_run() {
+            // chapters, cue sheets, and the rest come from the built album
+            format.transcode(
+                &settings,
+                &old_dir.join(format!("{}.flac", album_title)),
+                &master_file,
+                &master_tags,
+                Some(&chapters_file),
+            );
+            continue;
+        }
+        let timeline = Timeline::load(&old_dir).expect("album timeline missing; rebuild the album");
+        fs::write(&chapters_file, timeline.ffmetadata(&album_title)).unwrap();
+        format.transcode(
+            &settings,
+            &old_dir.join(format!("{}.flac", album_title)),
+            &master_file,
+            &master_tags,
+            Some(&chapters_file),
+        );
+        fs::remove_file(chapters_file).ok();
+
+        if format.is_flac() {
+            crate::cue::embed_cuesheet(
+                &master_file,
+                &match format {
+                    ExportFormat::HiresFlac => {
+                        timeline.resampled(settings.sample_rate.unwrap_or(96000) as u64)
+                    }
+                    _ => timeline.clone(),
+                },
+            );
+        }
+        fs::write(
+            format_dir.join(format!("{}.cue", album_title)),
+            timeline.cue_sheet(
+                &album_title,
+                &master_file.file_name().unwrap().to_string_lossy(),
+                format.cue_file_type(),
+            ),
+        )
+        .unwrap();
+
+        // tracklists are mirrored as-is, with playlists pointed at this format's files
+        for file in album_data.tracklist_files() {
+            let path = old_dir.join(&file);
+            let new_path = format_dir.join(&file);
+            if path.extension().unwrap_or_default() == "m3u8" {
+                fs::write(
+                    new_path,
+                    retarget_playlist(&fs::read_to_string(&path).unwrap(), format.extension()),
+                )
+                .unwrap();
+            } else {
+                fs::copy(&path, new_path).unwrap();
+            }
+        }
+    }
+}
+
+pub fn plan_arg(matches: &clap::ArgMatches) {
+    let album_name = AlbumName::new_from_arg(matches);
+    AlbumData::load_from_track(&album_name).plan(matches);
+}
+
+pub fn status_arg(matches: &clap::ArgMatches) {
+    print_status(&AlbumName::new_from_arg(matches), matches);
+}
+
+pub fn print_status(album_name: &AlbumName, matches: &clap::ArgMatches) {
+    AlbumData::load_from_track(album_name).status(matches);
 }
 
 pub fn build_arg(matches: &clap::ArgMatches) {
@@ -78,7 +222,7 @@ pub fn get_albums(matches: &clap::ArgMatches) -> Vec<AlbumName> {
     fs::read_dir(
         matches
             .value_of("album_dir")
-            .map(|dirname| PathBuf::from(dirname))
+            .map(PathBuf::from)
             .unwrap_or(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("albums")),
     )
     .unwrap()
@@ -88,8 +232,9 @@ pub fn get_albums(matches: &clap::ArgMatches) -> Vec<AlbumName> {
 
 pub fn build_album(album_name: AlbumName, matches: &clap::ArgMatches) {
     println!("> Building album {}", album_name);
+    let _lock = album_name.lock();
     println!("> Loading config file");
     let config = AlbumData::load_from_track(&album_name);
 
-    config.compile(&matches);
+    config.compile(matches);
 }
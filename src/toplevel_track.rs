@@ -1,10 +1,13 @@
 use std::fs;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::cache::Cache;
-use crate::clip::ClipProcess;
-use crate::config::TrackData;
+use crate::cache::{Cache, CacheTarget};
+use crate::clip::{ClipProcess, ClipsOpt};
+use crate::command;
+use crate::config::{Stage, TrackConfig, TrackData};
+use crate::lockfile;
 use crate::sox_args::SoxArgs;
 use crate::track_name::TrackName;
 
@@ -18,15 +21,16 @@ pub fn export_arg(matches: &clap::ArgMatches) {
     println!("Exporting...");
     let track_name = TrackName::new_from_arg(matches);
     assert!(
-        Command::new("cp")
-            .arg(track_name.dest_dir().join(TrackData::processed_filename()))
-            .arg(matches.value_of("output_file").unwrap())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()
-            .expect("export command failed")
-            .status
-            .success(),
+        command::run(
+            Command::new("cp")
+                .arg(track_name.dest_dir().join(TrackData::processed_filename()))
+                .arg(matches.value_of("output_file").unwrap())
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+        )
+        .expect("export command failed")
+        .status
+        .success(),
         "export command failed"
     );
 }
@@ -47,15 +51,89 @@ pub fn play_arg(matches: &clap::ArgMatches) {
     );
 }
 
+pub fn status_arg(matches: &clap::ArgMatches) {
+    print_status(&TrackName::new_from_arg(matches));
+}
+
+pub fn explain_arg(matches: &clap::ArgMatches) {
+    print_explanation(&TrackName::new_from_arg(matches));
+}
+
+// Track data as `status` and `explain` see it
+// Sources are checked too, but nothing is fetched, downloaded, copied, or created
+fn load_without_building(track_name: &TrackName) -> TrackData {
+    let mut config = TrackData::load_from_track(track_name);
+    config.check_sources(track_name);
+    config
+}
+
+// A row per stage, saying whether it would run and why
+pub fn print_status(track_name: &TrackName) {
+    let config = load_without_building(track_name);
+    let mut stage = match config.build() {
+        Some(_) => Some(Stage::Build),
+        None => Some(Stage::Raw),
+    };
+    println!("Track {}", track_name);
+    println!("  {:<6}  {:<10}  Reason", "Stage", "Status");
+    while let Some(s) = stage {
+        let (status, reason) = s.status(track_name, &config.updates);
+        let row = format!("  {:<6}  {:<10}  {}", s.name(), status, reason);
+        println!("{}", row.trim_end());
+        stage = s.next();
+    }
+}
+
+// Set by `build --explain`, so every track explains itself before building
+static EXPLAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn explain_builds() {
+    EXPLAIN.store(true, Ordering::Relaxed);
+}
+
+// Set for the `sotclk build` processes started by build-all -j, since the scheduler
+// has already built everything they depend on
+static SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_scheduled() {
+    SCHEDULED.store(true, Ordering::Relaxed);
+}
+
+// Every stale stage, with each config field, source, and file that changed
+pub fn print_explanation(track_name: &TrackName) {
+    let config = load_without_building(track_name);
+    if config.updates.reasons.is_empty() {
+        println!("--> Track '{}' is up to date", track_name);
+        return;
+    }
+    println!("--> Why track '{}' would rebuild", track_name);
+    let mut stage = Some(Stage::Build);
+    while let Some(s) = stage {
+        if let Some(reason) = config.updates.reason(s) {
+            println!("---> {} stage: {}", s.name(), reason);
+            for change in s.explain(track_name, &config.track_config) {
+                if change != reason {
+                    println!("       {}", change);
+                }
+            }
+        }
+        stage = s.next();
+    }
+}
+
 pub fn build_arg(matches: &clap::ArgMatches) {
-    build_track(TrackName::new_from_arg(matches));
+    let track_name = TrackName::new_from_arg(matches);
+    match SCHEDULED.load(Ordering::Relaxed) {
+        true => build_one(track_name),
+        false => build_track(track_name),
+    }
 }
 
 pub fn get_tracks(matches: &clap::ArgMatches) -> Vec<TrackName> {
     fs::read_dir(
         matches
             .value_of("track_dir")
-            .map(|dirname| PathBuf::from(dirname))
+            .map(PathBuf::from)
             .unwrap_or(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tracks")),
     )
     .unwrap()
@@ -64,16 +142,55 @@ pub fn get_tracks(matches: &clap::ArgMatches) -> Vec<TrackName> {
 }
 
 pub fn process_tracks(matches: &clap::ArgMatches) {
-    for track_name in get_tracks(matches) {
-        build_track(track_name);
+    for track_name in build_order(get_tracks(matches)) {
+        build_one(track_name);
     }
 }
 
+// Tracks ordered so each comes after everything in its [build] depends_on
+// Tracks that are depended on but weren't asked for are added
+pub fn build_order(tracks: Vec<TrackName>) -> Vec<TrackName> {
+    let mut order = vec![];
+    for track_name in tracks {
+        visit(track_name, &mut vec![], &mut order);
+    }
+    order
+}
+
+// `path` is the chain of dependencies that led here, for reporting cycles
+fn visit(track_name: TrackName, path: &mut Vec<String>, order: &mut Vec<TrackName>) {
+    let name = track_name.get_name();
+    if order.iter().any(|t| t.get_name() == name) {
+        return;
+    }
+    if let Some(start) = path.iter().position(|p| *p == name) {
+        panic!(
+            "Tracks depend on each other in a cycle: {} -> {}",
+            path[start..].join(" -> "),
+            name
+        );
+    }
+    if let Some(parent) = path.last() {
+        assert!(
+            track_name.source_dir().join("config.toml").exists(),
+            "Track '{}' depends on '{}', which is not in tracks/",
+            parent,
+            name
+        );
+    }
+    path.push(name);
+    for dep in TrackConfig::load(&track_name).dependencies() {
+        visit(track_name.sibling(&dep), path, order);
+    }
+    path.pop();
+    order.push(track_name);
+}
+
 pub fn setup_directories(matches: &clap::ArgMatches) {
     println!("Creating build directories...");
     let track_root_dir = matches
         .value_of("track_dir")
-        .map(|dirname| PathBuf::from(dirname))
+        .map(PathBuf::from)
         .unwrap_or(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tracks"));
     let target_root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target");
 
@@ -90,28 +207,50 @@ pub fn setup_directories(matches: &clap::ArgMatches) {
     }
 }
 
+// Builds a track, after building everything it depends on
+// Under build-all -j, that's all done by the time an album needs it
 pub fn build_track(track_name: TrackName) {
-    println!("-> Building track {}", track_name);
+    if SCHEDULED.load(Ordering::Relaxed) {
+        return;
+    }
+    for track_name in build_order(vec![track_name]) {
+        build_one(track_name);
+    }
+}
 
+fn build_one(track_name: TrackName) {
+    println!("-> Building track {}", track_name);
+    let _lock = track_name.lock();
+    // a reused build directory can't be rebuilt while this track is reading from it
+    let _shared_lock = TrackConfig::load(&track_name)
+        .build
+        .and_then(|b| b.reuse_build_dir)
+        .and_then(|shared| track_name.sibling(&shared).lock_shared());
+    if EXPLAIN.load(Ordering::Relaxed) {
+        print_explanation(&track_name);
+    }
     println!("--> Loading config file");
     let mut config = TrackData::load_from_track(&track_name);
+    let locked = lockfile::track_lock(&track_name);
 
     if let (Some(build_cfg), cache, updates) = (
         config.build().clone(),
         config.output().cache.unwrap_or(true),
         &mut config.updates,
     ) {
+        command::set_stage(format!("build stage of track '{}'", track_name));
         // Check download/clone status
         build_cfg.create_dirs(&track_name);
-        if build_cfg.git_sources.len() > 0 {
+        let record = Stage::Build.suspend(&track_name);
+        if !build_cfg.git_sources.is_empty() {
             println!("--> Downloading git sources");
-            if build_cfg.git(&track_name) {
+            if build_cfg.git(&track_name, locked) {
                 updates.build_updated();
             }
         }
-        if build_cfg.http_sources.len() > 0 {
+        if !build_cfg.http_sources.is_empty() {
             println!("--> Downloading http sources");
-            if build_cfg.http(&track_name, cache) {
+            if build_cfg.http(&track_name, cache, locked) {
                 updates.build_updated();
             }
         }
@@ -122,42 +261,63 @@ pub fn build_track(track_name: TrackName) {
             }
         }
         if updates.needs_build_update {
-            build_cfg.write_cache(&track_name);
+            Stage::Build.begin(&track_name, updates);
             build_cfg.wipe_build_progress(&track_name);
+        } else {
+            Stage::Build.resume(&track_name, record);
         }
-        if build_cfg.build_command.len() > 0 {
-            if build_cfg.run(&track_name) {
-                updates.rebuilt();
-            }
+        if !build_cfg.build_command.is_empty() && build_cfg.run(&track_name) {
+            updates.rebuilt();
+        }
+        if updates.needs_build_update {
+            build_cfg.cached().write_cache(&track_name);
         }
     }
 
+    // Records are only written once a stage has finished
     if config.updates.needs_raw_update {
-        config.output().write_cache(&track_name);
+        Stage::Raw.begin(&track_name, &config.updates);
         println!(
             "--> Running output command and dumping {} of data",
             config.output().output_buffer
         );
         println!("---> {}", &config.output().output_command);
         config.dump_raw(&track_name);
+        config.output().write_cache(&track_name);
+        Stage::Raw.record(&track_name, &config.track_config);
     } else {
         println!("--> Output generation up to date; continuing");
     }
 
     if config.updates.needs_preprocessed_update {
-        config.sox().write_cache(&track_name);
-
+        Stage::Sox.begin(&track_name, &config.updates);
         println!("--> Piping through sox");
         SoxArgs::new(&track_name, &config).execute();
+        config.sox().write_cache(&track_name);
+        Stage::Sox.record(&track_name, &config.track_config);
     } else {
         println!("--> Sox output up to date; continuing");
     }
 
     if config.updates.needs_ffmpeg_update {
-        config.clips().write_cache(&track_name);
+        Stage::Clip.begin(&track_name, &config.updates);
         config
             .clips()
             .process(&track_name, config.sox().tempo.unwrap_or(1.0)); // edit message is displayed internally, if required
+        ClipsOpt::from(config.track_config.clone()).write_cache(&track_name);
+        Stage::Clip.record(&track_name, &config.track_config);
+    }
+
+    if let (Some(lock), false) = (locked, command::dry_run()) {
+        let differences = lock.differences(&track_name);
+        if differences.is_empty() {
+            println!("--> Track '{}' matches sotclk.lock", track_name);
+        } else {
+            println!("--> Track '{}' doesn't match sotclk.lock:", track_name);
+            for difference in differences {
+                println!("---> {}", difference);
+            }
+        }
     }
 
     println!("--> Finished processing track '{}'", config.output().name);
@@ -0,0 +1,417 @@
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::album_data::{Album, Link};
+
+// One [[tracklist]] block in an album config
+// Built-in formats can have their file name or template pieces overridden
+// format = "template" needs at least `file` and `line`
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TracklistConfig {
+    pub format: String,
+    pub file: Option<String>,
+    pub header: Option<String>,
+    pub line: Option<String>,
+    pub footer: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TracklistEntry {
+    pub title: String,
+    pub track_id: String,
+    pub credits: Option<String>,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonTracklist<'a> {
+    album: &'a str,
+    artist: &'a Option<String>,
+    year: &'a Option<u32>,
+    description: &'a Option<String>,
+    notes: &'a Option<String>,
+    links: &'a Option<Vec<Link>>,
+    total_seconds: f64,
+    tracks: Vec<JsonTrack<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonTrack<'a> {
+    index: usize,
+    id: &'a str,
+    title: &'a str,
+    credits: &'a Option<String>,
+    file: String,
+    start: f64,
+    end: f64,
+    duration: f64,
+}
+
+pub struct Tracklist<'a> {
+    pub album: &'a Album,
+    pub entries: Vec<TracklistEntry>,
+    pub total: Duration,
+}
+
+impl TracklistConfig {
+    fn builtin(format: &str) -> Self {
+        Self {
+            format: format.to_owned(),
+            file: None,
+            header: None,
+            line: None,
+            footer: None,
+        }
+    }
+    // used when an album has no [[tracklist]] blocks
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::builtin("text"), Self::builtin("readme")]
+    }
+    // (file, header, line, footer)
+    fn template(&self) -> (String, String, String, String) {
+        let (file, header, line, footer) = match self.format.as_str() {
+            "text" => (
+                "tracklist.txt",
+                "{album}\n{about}\nTracklist:\n",
+                "{start} {title}\n",
+                "{credits_list}{links}{notes}",
+            ),
+            "youtube" => (
+                "youtube.txt",
+                "{links}{about}\nTracklist:\n",
+                "{start} {title}\n",
+                "{credits_list}{notes}",
+            ),
+            "readme" => (
+                "README.md",
+                "# {album}\n{about}{links}\nTracklist:\n",
+                "{start} {title}\n",
+                "{credits_list}{notes}",
+            ),
+            "markdown" => (
+                "tracklist.md",
+                "# {album}\n{about}\n| # | Start | Title | Length |\n|---|---|---|---|\n",
+                "| {index} | {start} | {title} | {duration} |\n",
+                "\nTotal length: {total}\n{credits_list}{links}{notes}",
+            ),
+            "m3u8" => (
+                "playlist.m3u8",
+                "#EXTM3U\n#PLAYLIST:{album}\n",
+                "#EXTINF:{seconds},{title}\n{file}\n",
+                "",
+            ),
+            "template" => ("", "", "", ""),
+            other => panic!(
+                "tracklist format '{}' is invalid. \
+		 Valid options are: text, youtube, readme, markdown, json, m3u8, template.",
+                other
+            ),
+        };
+        let pick = |o: &Option<String>, d: &str| o.clone().unwrap_or_else(|| d.to_owned());
+        let file = pick(&self.file, file);
+        let line = pick(&self.line, line);
+        assert!(
+            !file.is_empty() && !line.is_empty(),
+            "tracklist templates need both `file` and `line`"
+        );
+        (
+            file,
+            pick(&self.header, header),
+            line,
+            pick(&self.footer, footer),
+        )
+    }
+    pub fn file_name(&self) -> String {
+        match self.format.as_str() {
+            "json" => self
+                .file
+                .clone()
+                .unwrap_or_else(|| "tracklist.json".to_owned()),
+            _ => self.template().0,
+        }
+    }
+}
+
+// H:MM:SS past an hour, M:SS otherwise
+pub fn format_timestamp(time: Duration, with_hours: bool) -> String {
+    let secs = time.num_seconds().max(0);
+    if with_hours {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+fn seconds(time: Duration) -> f64 {
+    time.num_milliseconds() as f64 / 1000.0
+}
+
+impl<'a> Tracklist<'a> {
+    fn track_file(entry: &TracklistEntry) -> String {
+        format!(
+            "{}/{}.flac",
+            crate::album_data::AlbumData::track_dir_name(),
+            entry.title
+        )
+    }
+    // Blocks are empty when unset. Otherwise they start with a blank line
+    fn block(content: String) -> String {
+        if content.is_empty() {
+            content
+        } else {
+            format!("\n{}\n", content.trim_end())
+        }
+    }
+    fn about(&self) -> String {
+        let byline = match (&self.album.artist, self.album.year) {
+            (Some(artist), Some(year)) => format!("by {} ({})\n", artist, year),
+            (Some(artist), None) => format!("by {}\n", artist),
+            (None, Some(year)) => format!("{}\n", year),
+            (None, None) => "".to_owned(),
+        };
+        Self::block(byline + self.album.description.as_deref().unwrap_or(""))
+    }
+    fn links(&self) -> String {
+        match &self.album.links {
+            Some(links) if !links.is_empty() => Self::block(
+                "Releases:\n".to_owned()
+                    + &links
+                        .iter()
+                        .map(|l| format!("- [{}]({})\n", l.name, l.url))
+                        .collect::<String>(),
+            ),
+            _ => "".to_owned(),
+        }
+    }
+    fn credits_list(&self) -> String {
+        let credits = self
+            .entries
+            .iter()
+            .filter_map(|e| {
+                e.credits
+                    .as_ref()
+                    .map(|c| format!("- {}: {}\n", e.title, c))
+            })
+            .collect::<String>();
+        if credits.is_empty() {
+            credits
+        } else {
+            Self::block("Credits:\n".to_owned() + &credits)
+        }
+    }
+    // Placeholders are looked up in one pass over the template, so a `{name}` that turns up
+    // in a title or in the notes is left as it is. Unknown ones are kept too
+    fn fill(&self, template: &str, entry: Option<(usize, &TracklistEntry)>) -> String {
+        let with_hours = self.total >= Duration::hours(1);
+        let mut values: BTreeMap<&str, String> = BTreeMap::new();
+        values.insert("album", self.album.title.clone());
+        values.insert("artist", self.album.artist.clone().unwrap_or_default());
+        values.insert(
+            "year",
+            self.album.year.map(|y| y.to_string()).unwrap_or_default(),
+        );
+        values.insert("about", self.about());
+        values.insert("links", self.links());
+        values.insert("credits_list", self.credits_list());
+        values.insert(
+            "notes",
+            Self::block(self.album.notes.clone().unwrap_or_default()),
+        );
+        values.insert("total", format_timestamp(self.total, with_hours));
+        if let Some((i, entry)) = entry {
+            values.insert("index", (i + 1).to_string());
+            values.insert("title", entry.title.clone());
+            values.insert("track", entry.track_id.clone());
+            values.insert("credits", entry.credits.clone().unwrap_or_default());
+            values.insert("start", format_timestamp(entry.start, with_hours));
+            values.insert(
+                "end",
+                format_timestamp(entry.start + entry.duration, with_hours),
+            );
+            values.insert("duration", format_timestamp(entry.duration, with_hours));
+            values.insert("seconds", entry.duration.num_seconds().to_string());
+            values.insert("file", Self::track_file(entry));
+        }
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            out.push_str(&rest[..open]);
+            rest = &rest[open..];
+            let value = rest
+                .find('}')
+                .and_then(|close| Some((close, values.get(&rest[1..close])?)));
+            match value {
+                Some((close, value)) => {
+                    out.push_str(value);
+                    rest = &rest[close + 1..];
+                }
+                None => {
+                    out.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+    pub fn render(&self, config: &TracklistConfig) -> String {
+        if config.format == "json" {
+            return serde_json::to_string_pretty(&JsonTracklist {
+                album: &self.album.title,
+                artist: &self.album.artist,
+                year: &self.album.year,
+                description: &self.album.description,
+                notes: &self.album.notes,
+                links: &self.album.links,
+                total_seconds: seconds(self.total),
+                tracks: self
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, e)| JsonTrack {
+                        index: i + 1,
+                        id: &e.track_id,
+                        title: &e.title,
+                        credits: &e.credits,
+                        file: Self::track_file(e),
+                        start: seconds(e.start),
+                        end: seconds(e.start + e.duration),
+                        duration: seconds(e.duration),
+                    })
+                    .collect(),
+            })
+            .unwrap()
+                + "\n";
+        }
+        let (_, header, line, footer) = config.template();
+        (self.fill(&header, None)
+            + &self
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(i, e)| self.fill(&line, Some((i, e))))
+                .collect::<String>()
+            + &self.fill(&footer, None))
+            .trim_start_matches('\n')
+            .to_owned()
+    }
+    pub fn write_all(&self, configs: &[TracklistConfig], dir: &Path) {
+        for config in configs {
+            let path = dir.join(config.file_name());
+            println!("---> Writing {}", path.display());
+            fs::write(path, self.render(config)).unwrap();
+        }
+    }
+}
+
+// Playlists point at individual_tracks/*.flac
+// Exports in other formats need them pointed at their own files
+pub fn retarget_playlist(contents: &str, extension: &str) -> String {
+    contents
+        .lines()
+        .map(|l| match (l.starts_with('#'), l.strip_suffix(".flac")) {
+            (false, Some(stem)) => format!("{}.{}\n", stem, extension),
+            _ => format!("{}\n", l),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn album() -> Album {
+        toml::from_str(
+            r#"
+            title = "Sounds of {artist}"
+            artist = "Tux"
+            notes = "Recorded live"
+            tracks = []
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn entry(title: &str) -> TracklistEntry {
+        TracklistEntry {
+            title: title.to_owned(),
+            track_id: "kernelbuild".to_owned(),
+            credits: None,
+            start: Duration::seconds(65),
+            duration: Duration::seconds(3725),
+        }
+    }
+
+    fn fill(total: Duration, template: &str, title: &str) -> String {
+        let album = album();
+        let tracklist = Tracklist {
+            album: &album,
+            entries: vec![],
+            total,
+        };
+        tracklist.fill(template, Some((0, &entry(title))))
+    }
+
+    #[test]
+    fn placeholders_in_values_are_left_alone() {
+        let total = Duration::seconds(59);
+        assert_eq!(
+            fill(total, "{index}. {title}", "Dumping {notes}"),
+            "1. Dumping {notes}"
+        );
+        assert_eq!(
+            fill(total, "{album} by {artist}", ""),
+            "Sounds of {artist} by Tux"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_and_stray_braces_are_kept() {
+        let total = Duration::seconds(59);
+        assert_eq!(fill(total, "{foo} {title}", "t"), "{foo} t");
+        assert_eq!(fill(total, "{ {title} {", "t"), "{ t {");
+        assert_eq!(fill(total, "{{title}}", "t"), "{t}");
+        assert_eq!(fill(total, "{title", "t"), "{title");
+    }
+
+    #[test]
+    fn album_templates_leave_entry_placeholders() {
+        let album = album();
+        let tracklist = Tracklist {
+            album: &album,
+            entries: vec![],
+            total: Duration::seconds(59),
+        };
+        assert_eq!(tracklist.fill("{total} {title}", None), "0:59 {title}");
+    }
+
+    #[test]
+    fn timestamps_gain_hours_with_long_albums() {
+        assert_eq!(
+            fill(Duration::seconds(59), "{start}-{end} ({duration})", "t"),
+            "1:05-63:10 (62:05)"
+        );
+        assert_eq!(
+            fill(Duration::hours(2), "{start}-{end} ({duration})", "t"),
+            "0:01:05-1:03:10 (1:02:05)"
+        );
+    }
+
+    #[test]
+    fn format_timestamp_pads_minutes_and_seconds() {
+        assert_eq!(format_timestamp(Duration::seconds(5), false), "0:05");
+        assert_eq!(format_timestamp(Duration::seconds(605), false), "10:05");
+        assert_eq!(format_timestamp(Duration::seconds(3605), false), "60:05");
+        assert_eq!(format_timestamp(Duration::seconds(3605), true), "1:00:05");
+        assert_eq!(format_timestamp(Duration::seconds(65), true), "0:01:05");
+        assert_eq!(
+            format_timestamp(Duration::milliseconds(1999), false),
+            "0:01"
+        );
+        assert_eq!(format_timestamp(Duration::seconds(-3), false), "0:00");
+    }
+}